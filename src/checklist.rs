@@ -0,0 +1,107 @@
+//! Checklists inside note text.
+//!
+//! A line of a note starting with `[ ]` (open) or `[x]` (done), optionally
+//! after a `-` or `*` bullet and indentation, is a checklist item:
+//!
+//! ```text
+//! Review before release
+//! - [x] check licenses
+//! - [ ] update changelog
+//! ```
+//!
+//! Items are numbered from 1 in the order they appear.
+
+/// A checklist item found in a note.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    /// Index of the line the item is on.
+    line: usize,
+    /// Byte offset of the checkbox within the line.
+    box_at: usize,
+    pub done: bool,
+    pub text: String,
+}
+
+/// Parse a line as a checklist item.
+fn parse_item(line_index: usize, line: &str) -> Option<Item> {
+    let trimmed = line.trim_start();
+    let bulleted = trimmed
+        .strip_prefix("- ")
+        .or_else(|| trimmed.strip_prefix("* "))
+        .unwrap_or(trimmed);
+    let box_at = line.len() - bulleted.len();
+    let done = match bulleted.get(..3)? {
+        "[ ]" => false,
+        "[x]" | "[X]" => true,
+        _ => return None,
+    };
+    let rest = &bulleted[3..];
+    if !(rest.is_empty() || rest.starts_with(char::is_whitespace)) {
+        return None;
+    }
+    Some(Item {
+        line: line_index,
+        box_at,
+        done,
+        text: rest.trim().to_string(),
+    })
+}
+
+/// The checklist items in a note's text.
+pub fn items(text: &str) -> Vec<Item> {
+    text.lines()
+        .enumerate()
+        .filter_map(|(i, line)| parse_item(i, line))
+        .collect()
+}
+
+/// How many of a note's checklist items are done, out of how many, or
+/// `None` if the note has no checklist.
+pub fn progress(text: &str) -> Option<(usize, usize)> {
+    let items = items(text);
+    if items.is_empty() {
+        return None;
+    }
+    Some((items.iter().filter(|i| i.done).count(), items.len()))
+}
+
+/// Add an open item to the end of a note's text.
+pub fn add_item(text: &str, item: &str) -> String {
+    let line = format!("- [ ] {}", item.trim());
+    if text.trim().is_empty() {
+        line
+    } else {
+        format!("{}\n{}", text.trim_end_matches('\n'), line)
+    }
+}
+
+/// Mark item `number` (counting from 1) done or open again.
+pub fn set_done(text: &str, number: usize, done: bool) -> Result<String, String> {
+    let items = items(text);
+    let item = number
+        .checked_sub(1)
+        .and_then(|i| items.get(i))
+        .ok_or_else(|| match items.len() {
+            0 => "The note has no checklist".to_string(),
+            n => format!("No item {} (the checklist has {})", number, n),
+        })?;
+
+    let mark = if done { "[x]" } else { "[ ]" };
+    let lines: Vec<String> = text
+        .split('\n')
+        .enumerate()
+        .map(|(i, line)| {
+            if i == item.line {
+                format!(
+                    "{}{}{}",
+                    &line[..item.box_at],
+                    mark,
+                    &line[item.box_at + 3..]
+                )
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+    Ok(lines.join("\n"))
+}
@@ -15,8 +15,16 @@ const CONFIG_FILE: &str = "config";
 static CONFIG: OnceLock<Config> = OnceLock::new();
 
 /// Returns a reference to the global configuration.
+///
+/// Exits with an error if the config file has an invalid setting, rather than
+/// guessing at what was meant (and, say, storing notes in the wrong place).
 pub fn get_config() -> &'static Config {
-    CONFIG.get_or_init(|| Config::load().unwrap_or_default())
+    CONFIG.get_or_init(|| {
+        Config::load().unwrap_or_else(|e| {
+            eprintln!("Error in config: {}", e);
+            std::process::exit(1);
+        })
+    })
 }
 
 /// Main configuration structure.
@@ -25,6 +33,7 @@ pub struct Config {
     pub icons: IconsConfig,
     pub colors: ColorsConfig,
     pub git: GitConfig,
+    pub notes: NotesConfig,
 }
 
 /// Icon configuration for different file types.
@@ -34,6 +43,13 @@ pub struct IconsConfig {
     pub symlink: String,
     pub file: String,
     pub executable: String,
+    /// Shown on ghost entries for notes on paths that don't exist yet.
+    pub pending: String,
+    /// Shown before a note's to-do and warning.
+    pub todo: String,
+    pub warn: String,
+    /// Shown before checklist progress.
+    pub checklist: String,
     pub extensions: HashMap<String, String>,
     pub filenames: HashMap<String, String>,
 }
@@ -48,6 +64,16 @@ pub struct ColorsConfig {
     pub git_modified: String,
     pub git_staged: String,
     pub git_untracked: String,
+    pub tag: String,
+    /// Notes whose expiry date has passed.
+    pub expired: String,
+    /// `code` spans in notes.
+    pub code: String,
+    /// A note's to-do and warning.
+    pub todo: String,
+    pub warn: String,
+    /// Checklist progress.
+    pub checklist: String,
 }
 
 /// Git status symbol configuration.
@@ -59,6 +85,40 @@ pub struct GitConfig {
     pub ignored: String,
 }
 
+/// Note storage configuration.
+#[derive(Debug, Clone)]
+pub struct NotesConfig {
+    /// Record a content hash with each note, used to confirm a moved file is
+    /// the same file before its note is re-associated.
+    pub content_hash: bool,
+    /// Show the global note rather than the project note when a file has both.
+    pub prefer_global: bool,
+    /// Where global notes are stored.
+    pub backend: Backend,
+    /// Text placed between a note and text added with --append or --prepend.
+    pub separator: String,
+    /// File whose contents unlock private notes instead of a passphrase.
+    pub key_file: Option<PathBuf>,
+    /// Shared directory `lsnote notes sync` merges the global notes with.
+    pub sync_dir: Option<PathBuf>,
+}
+
+/// Storage backend for global notes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// The global notes file.
+    #[default]
+    File,
+    /// Extended attributes on each file, falling back to the notes file.
+    #[value(skip)]
+    Xattr,
+    /// A SQLite database, for large note collections.
+    Sqlite,
+    /// Git notes in the file's repository, falling back to the notes file.
+    #[value(skip)]
+    Git,
+}
+
 impl Default for IconsConfig {
     fn default() -> Self {
         let mut extensions = HashMap::new();
@@ -178,6 +238,10 @@ impl Default for IconsConfig {
             symlink: "🔗".into(),
             file: "📄".into(),
             executable: "📄".into(),
+            pending: "👻".into(),
+            todo: "☐".into(),
+            warn: "⚠".into(),
+            checklist: "☑".into(),
             extensions,
             filenames,
         }
@@ -194,6 +258,12 @@ impl Default for ColorsConfig {
             git_modified: "red".into(),
             git_staged: "green".into(),
             git_untracked: "yellow".into(),
+            tag: "cyan".into(),
+            expired: "yellow".into(),
+            code: "green".into(),
+            todo: "magenta".into(),
+            warn: "red".into(),
+            checklist: "green".into(),
         }
     }
 }
@@ -209,6 +279,19 @@ impl Default for GitConfig {
     }
 }
 
+impl Default for NotesConfig {
+    fn default() -> Self {
+        Self {
+            content_hash: false,
+            prefer_global: false,
+            backend: Backend::File,
+            separator: "\n".into(),
+            key_file: None,
+            sync_dir: None,
+        }
+    }
+}
+
 impl Config {
     /// Load configuration from ~/.lsnote/config.
     pub fn load() -> Result<Self, String> {
@@ -229,19 +312,23 @@ impl Config {
             }
 
             if let Some((key, value)) = line.split_once('=') {
-                config.set(key.trim(), value.trim());
+                config.set(key.trim(), value.trim())?;
             }
         }
 
         Ok(config)
     }
 
-    fn set(&mut self, key: &str, value: &str) {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
         match key {
             "icon.directory" => self.icons.directory = value.into(),
             "icon.symlink" => self.icons.symlink = value.into(),
             "icon.file" => self.icons.file = value.into(),
             "icon.executable" => self.icons.executable = value.into(),
+            "icon.pending" => self.icons.pending = value.into(),
+            "icon.todo" => self.icons.todo = value.into(),
+            "icon.warn" => self.icons.warn = value.into(),
+            "icon.checklist" => self.icons.checklist = value.into(),
             "color.directory" => self.colors.directory = value.into(),
             "color.symlink" => self.colors.symlink = value.into(),
             "color.executable" => self.colors.executable = value.into(),
@@ -249,10 +336,35 @@ impl Config {
             "color.git_modified" => self.colors.git_modified = value.into(),
             "color.git_staged" => self.colors.git_staged = value.into(),
             "color.git_untracked" => self.colors.git_untracked = value.into(),
+            "color.tag" => self.colors.tag = value.into(),
+            "color.expired" => self.colors.expired = value.into(),
+            "color.code" => self.colors.code = value.into(),
+            "color.todo" => self.colors.todo = value.into(),
+            "color.warn" => self.colors.warn = value.into(),
+            "color.checklist" => self.colors.checklist = value.into(),
             "git.modified" => self.git.modified = value.into(),
             "git.staged" => self.git.staged = value.into(),
             "git.untracked" => self.git.untracked = value.into(),
             "git.ignored" => self.git.ignored = value.into(),
+            "notes.content_hash" => self.notes.content_hash = parse_bool(value),
+            "notes.precedence" => self.notes.prefer_global = value.eq_ignore_ascii_case("global"),
+            "notes.backend" => {
+                self.notes.backend = match value.to_lowercase().as_str() {
+                    "xattr" => Backend::Xattr,
+                    "sqlite" => Backend::Sqlite,
+                    "git" => Backend::Git,
+                    "file" => Backend::File,
+                    _ => {
+                        return Err(format!(
+                            "unknown notes.backend '{}' (expected file, xattr, sqlite or git)",
+                            value
+                        ))
+                    }
+                }
+            }
+            "notes.separator" => self.notes.separator = parse_text(value),
+            "notes.key_file" => self.notes.key_file = Some(parse_path(value)),
+            "notes.sync_dir" => self.notes.sync_dir = Some(parse_path(value)),
             _ if key.starts_with("icon.ext.") => {
                 let ext = &key[9..];
                 self.icons.extensions.insert(ext.into(), value.into());
@@ -265,6 +377,7 @@ impl Config {
             }
             _ => {}
         }
+        Ok(())
     }
 
     /// Generate default configuration file content.
@@ -279,6 +392,10 @@ impl Config {
             "icon.symlink = 🔗",
             "icon.file = 📄",
             "icon.executable = 📄",
+            "icon.pending = 👻",
+            "icon.todo = ☐",
+            "icon.warn = ⚠",
+            "icon.checklist = ☑",
             "",
             "# Colors (black, red, green, yellow, blue, magenta, cyan, white)",
             "# Also: bright_black, bright_red, bright_green, etc.",
@@ -289,6 +406,12 @@ impl Config {
             "color.git_modified = red",
             "color.git_staged = green",
             "color.git_untracked = yellow",
+            "color.tag = cyan",
+            "color.expired = yellow",
+            "color.code = green",
+            "color.todo = magenta",
+            "color.warn = red",
+            "color.checklist = green",
             "",
             "# Git status symbols",
             "git.modified = ●",
@@ -296,6 +419,20 @@ impl Config {
             "git.untracked = ?",
             "git.ignored = ◌",
             "",
+            "# Notes",
+            "# Hash file contents so notes follow renamed files more reliably",
+            "notes.content_hash = false",
+            "# Which note wins when both project and global notes exist (project, global)",
+            "notes.precedence = project",
+            "# Where global notes are stored (file, xattr, sqlite, git)",
+            "notes.backend = file",
+            "# Text between a note and text added with --append/--prepend (\\n = newline)",
+            "notes.separator = \\n",
+            "# File whose contents unlock private notes, instead of a passphrase",
+            "# notes.key_file = ~/.lsnote/key",
+            "# Shared directory (e.g. synced by Syncthing) for `lsnote notes sync`",
+            "# notes.sync_dir = ~/Sync/lsnote",
+            "",
             "# Extension icons (icon.ext.<extension> = <icon>)",
             "# icon.ext.rs = 🦀",
             "# icon.ext.py = 🐍",
@@ -333,6 +470,29 @@ pub fn init_config() -> Result<PathBuf, String> {
     Ok(config_path)
 }
 
+/// Parse a boolean config value.
+fn parse_bool(value: &str) -> bool {
+    matches!(value.to_lowercase().as_str(), "true" | "yes" | "on" | "1")
+}
+
+/// Parse a text value, which may be quoted to keep surrounding spaces and
+/// may use `\n` and `\t` for newline and tab.
+fn parse_text(value: &str) -> String {
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+    value.replace("\\n", "\n").replace("\\t", "\t")
+}
+
+/// Parse a path value, expanding a leading `~/` to the home directory.
+fn parse_path(value: &str) -> PathBuf {
+    match (value.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(value),
+    }
+}
+
 /// Parse a color name string into a Color enum.
 pub fn parse_color(name: &str) -> Color {
     match name.to_lowercase().as_str() {
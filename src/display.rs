@@ -2,18 +2,23 @@
 //!
 //! Handles directory listing, tree view, and entry formatting.
 
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fs::{self, Metadata, Permissions};
 use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 use chrono::{DateTime, Local};
-use colored::Colorize;
+use colored::{Color, Colorize};
 
+use crate::checklist;
 use crate::config::{get_config, parse_color};
 use crate::git::{format_git_status_ex, get_git_statuses, GitStatus};
 use crate::icons::{get_icon, is_executable};
-use crate::notes::get_note;
+use crate::markdown::parse_inline;
+use crate::notes::{tag_spans, Kind, Note, NotesStore};
+use crate::private::{NoteKey, LOCKED};
 
 /// Display options for listing.
 #[derive(Clone)]
@@ -26,6 +31,56 @@ pub struct DisplayOptions {
     #[allow(dead_code)]
     pub tree_view: bool,
     pub show_header: bool,
+    /// Notes loaded once and shared by every entry in the listing.
+    pub notes: Rc<NotesStore>,
+    /// Only show entries whose note carries this tag.
+    pub tag: Option<String>,
+    /// Only show entries whose note has this slot set.
+    pub kind: Option<Kind>,
+    /// Show how long ago each note was last changed (long format only).
+    pub show_note_age: bool,
+    /// Key to decrypt private notes with, if they were unlocked.
+    pub key: Option<&'static NoteKey>,
+}
+
+/// The note shown on an entry.
+struct ShownNote<'a> {
+    note: Cow<'a, Note>,
+    /// Inherited from an enclosing directory rather than the entry's own.
+    inherited: bool,
+}
+
+impl<'a> ShownNote<'a> {
+    fn own(note: &'a Note) -> Self {
+        Self {
+            note: Cow::Borrowed(note),
+            inherited: false,
+        }
+    }
+
+    /// Look up the note to show for an entry: its own (or a matching
+    /// pattern's), or else one inherited from an enclosing directory.
+    fn lookup(abs_path: &Path, opts: &'a DisplayOptions) -> Option<Self> {
+        if let Some(note) = opts.notes.get_canonical(abs_path) {
+            return Some(Self {
+                note: reveal(note, opts),
+                inherited: false,
+            });
+        }
+        opts.notes.get_inherited(abs_path).map(|note| Self {
+            note: reveal(Cow::Owned(note), opts),
+            inherited: true,
+        })
+    }
+}
+
+/// Decrypt a private note if private notes were unlocked. A note that stays
+/// encrypted is shown as locked.
+fn reveal<'a>(note: Cow<'a, Note>, opts: &DisplayOptions) -> Cow<'a, Note> {
+    match &opts.key {
+        Some(key) if note.private => key.reveal(&note).map_or(note, Cow::Owned),
+        _ => note,
+    }
 }
 
 /// Format a size in bytes to human-readable format.
@@ -48,6 +103,22 @@ fn format_size(size: u64) -> String {
     }
 }
 
+/// Format the time since a Unix timestamp compactly (e.g., "5m", "3d").
+pub fn format_age(timestamp: i64) -> String {
+    let secs = (chrono::Utc::now().timestamp() - timestamp).max(0);
+    if secs < 60 {
+        "now".to_string()
+    } else if secs < 3600 {
+        format!("{}m", secs / 60)
+    } else if secs < 86400 {
+        format!("{}h", secs / 3600)
+    } else if secs < 365 * 86400 {
+        format!("{}d", secs / 86400)
+    } else {
+        format!("{}y", secs / (365 * 86400))
+    }
+}
+
 /// Colorize a file name based on its type and git status.
 pub fn colorize_name(name: &str, metadata: &Metadata, git_status: Option<&GitStatus>) -> String {
     let config = get_config();
@@ -98,6 +169,260 @@ pub fn colorize_name(name: &str, metadata: &Metadata, git_status: Option<&GitSta
     }
 }
 
+/// Checklist progress of the note shown on an entry, unless inherited.
+fn note_progress(note: Option<&ShownNote>) -> Option<(usize, usize)> {
+    note.filter(|n| !n.inherited && !n.note.private)
+        .and_then(|n| checklist::progress(&n.note.text))
+}
+
+/// Format checklist progress as the ` ☑ 3/5` shown after an entry's name.
+fn format_progress(progress: Option<(usize, usize)>, for_display: bool) -> String {
+    let Some((done, total)) = progress else {
+        return String::new();
+    };
+    let config = get_config();
+    let progress = format!("{} {}/{}", config.icons.checklist, done, total);
+    if for_display {
+        format!(" {}", progress.color(parse_color(&config.colors.checklist)))
+    } else {
+        format!(" {}", progress)
+    }
+}
+
+/// Format a note as the `  # note` suffix of an entry, followed by its
+/// to-do and warning, each after its icon.
+/// Multi-line notes are joined onto one line to keep one entry per line.
+fn format_note(shown: &ShownNote, for_display: bool) -> String {
+    if shown.note.private {
+        return format!("  # {}", LOCKED);
+    }
+    let mut output = shown
+        .note
+        .slot(Kind::Note)
+        .map(|text| format_text(shown, text, for_display))
+        .unwrap_or_default();
+
+    for (kind, icon, color) in slot_styles() {
+        let Some(text) = shown.note.slot(kind) else {
+            continue;
+        };
+        let text = text.lines().collect::<Vec<_>>().join(" ↵ ");
+        let slot = if !for_display {
+            format!("{} {}", icon, text)
+        } else if shown.inherited {
+            format!("{} {}", icon, text).dimmed().to_string()
+        } else {
+            let color = parse_color(color);
+            format!(
+                "{} {}",
+                icon.color(color),
+                render_markdown(&text, Some(color), false)
+            )
+        };
+        output.push_str(&format!("  {}", slot));
+    }
+    output
+}
+
+/// The slots shown after a note's text, with their icons and colors.
+fn slot_styles() -> [(Kind, &'static str, &'static str); 2] {
+    let config = get_config();
+    [
+        (Kind::Todo, &config.icons.todo, &config.colors.todo),
+        (Kind::Warn, &config.icons.warn, &config.colors.warn),
+    ]
+}
+
+/// Format a note's text as the `  # note` part of its suffix.
+fn format_text(shown: &ShownNote, text: &str, for_display: bool) -> String {
+    let text = text.lines().collect::<Vec<_>>().join(" ↵ ");
+    if !for_display {
+        return format!("  # {}", text);
+    }
+    if shown.inherited {
+        return format!("  {}", format!("# {}", text).bright_black().dimmed());
+    }
+    if shown.note.is_expired() {
+        let color = parse_color(&get_config().colors.expired);
+        return format!(
+            "  {}{}",
+            "# ".color(color),
+            render_markdown(&text, Some(color), false)
+        );
+    }
+
+    format!(
+        "  {}{}",
+        "# ".bright_black(),
+        render_markdown(&text, Some(Color::BrightBlack), true)
+    )
+}
+
+/// Render note text for the terminal: inline Markdown styled on top of the
+/// `base` color (the terminal's own if `None`), with `#tags` picked out in
+/// the tag color if `tags` is set. Links become OSC 8 hyperlinks.
+fn render_markdown(text: &str, base: Option<Color>, tags: bool) -> String {
+    // Without colors the markers are all there is to show the formatting
+    if !colored::control::SHOULD_COLORIZE.should_colorize() {
+        return text.to_string();
+    }
+    let config = get_config();
+    let paint = |text: &str, color: Option<Color>| match color {
+        Some(color) => text.color(color),
+        None => text.normal(),
+    };
+
+    let mut rendered = String::new();
+    for span in parse_inline(text) {
+        let style = &span.style;
+        let mut pieces = Vec::new();
+        if style.code {
+            pieces.push(paint(&span.text, Some(parse_color(&config.colors.code))));
+        } else if tags && style.link.is_none() {
+            let tag_color = parse_color(&config.colors.tag);
+            let mut last = 0;
+            for range in tag_spans(&span.text) {
+                if range.start > last {
+                    pieces.push(paint(&span.text[last..range.start], base));
+                }
+                pieces.push(paint(&span.text[range.clone()], Some(tag_color)));
+                last = range.end;
+            }
+            if last < span.text.len() {
+                pieces.push(paint(&span.text[last..], base));
+            }
+        } else {
+            pieces.push(paint(&span.text, base));
+        }
+
+        let mut styled = String::new();
+        for mut piece in pieces {
+            if style.bold {
+                piece = piece.bold();
+            }
+            if style.italic {
+                piece = piece.italic();
+            }
+            if style.link.is_some() {
+                piece = piece.underline();
+            }
+            styled.push_str(&piece.to_string());
+        }
+        match &style.link {
+            Some(url) => {
+                rendered.push_str(&format!("\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\", url, styled));
+            }
+            _ => rendered.push_str(&styled),
+        }
+    }
+    rendered
+}
+
+/// Build the full text of a note for reading on its own, line by line,
+/// followed by its to-do and warning.
+///
+/// Besides inline Markdown, `#` headings, `-`/`*` list items and fenced code
+/// blocks are styled. The text is returned unchanged if not `for_display` or
+/// colors are off.
+pub fn build_note_view(note: &Note, for_display: bool) -> String {
+    if note.private {
+        return format!("{}\n", LOCKED);
+    }
+    let styled = for_display && colored::control::SHOULD_COLORIZE.should_colorize();
+    let mut output = match note.slot(Kind::Note) {
+        Some(text) if styled => build_text_view(text),
+        Some(text) => format!("{}\n", text),
+        None => String::new(),
+    };
+
+    for (kind, icon, color) in slot_styles() {
+        let Some(text) = note.slot(kind) else {
+            continue;
+        };
+        let text = text.lines().collect::<Vec<_>>().join("\n  ");
+        if styled {
+            let color = parse_color(color);
+            output.push_str(&format!(
+                "{} {}\n",
+                icon.color(color),
+                render_markdown(&text, Some(color), false)
+            ));
+        } else {
+            output.push_str(&format!("{} {}\n", icon, text));
+        }
+    }
+    output
+}
+
+/// Style a note's text for [`build_note_view`].
+fn build_text_view(text: &str) -> String {
+    let config = get_config();
+    let code_color = parse_color(&config.colors.code);
+    let mut output = String::new();
+    let mut in_code_block = false;
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") {
+            in_code_block = !in_code_block;
+            continue;
+        }
+        if in_code_block {
+            output.push_str(&format!("    {}\n", line.color(code_color)));
+            continue;
+        }
+
+        let hashes = trimmed.len() - trimmed.trim_start_matches('#').len();
+        let heading = trimmed[hashes..]
+            .strip_prefix(' ')
+            .filter(|_| (1..=6).contains(&hashes));
+        let item = trimmed
+            .strip_prefix("- ")
+            .or_else(|| trimmed.strip_prefix("* "));
+        if let Some(heading) = heading {
+            output.push_str(&render_markdown(heading, None, false).bold().to_string());
+        } else if let Some(item) = item {
+            let indent = &line[..line.len() - trimmed.len()];
+            output.push_str(&format!(
+                "{}• {}",
+                indent,
+                render_markdown(item, None, true)
+            ));
+        } else {
+            output.push_str(&render_markdown(line, None, true));
+        }
+        output.push('\n');
+    }
+    output
+}
+
+/// Check whether an entry passes the `--tag` and `--type` filters. With
+/// `within`, an entry also passes if anything inside it does (used by tree
+/// view).
+fn passes_filters(path: &Path, opts: &DisplayOptions, within: bool) -> bool {
+    if opts.tag.is_none() && opts.kind.is_none() {
+        return true;
+    }
+    let abs_path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if within {
+        opts.tag
+            .as_ref()
+            .is_none_or(|tag| opts.notes.has_tag_within(&abs_path, tag))
+            && opts
+                .kind
+                .is_none_or(|kind| opts.notes.has_kind_within(&abs_path, kind))
+    } else {
+        opts.notes
+            .get_canonical(&abs_path)
+            .is_some_and(|n| passes_note_filters(&n, opts))
+    }
+}
+
+/// Check whether a note passes the `--tag` and `--type` filters.
+fn passes_note_filters(note: &Note, opts: &DisplayOptions) -> bool {
+    opts.tag.as_ref().is_none_or(|tag| note.has_tag(tag))
+        && opts.kind.is_none_or(|kind| note.slot(kind).is_some())
+}
+
 /// Format file permissions as a string (e.g., "drwxr-xr-x").
 fn format_permissions(permissions: Permissions, metadata: &Metadata) -> String {
     let mode = permissions.mode();
@@ -177,14 +502,17 @@ pub fn build_list(path: &Path, opts: &DisplayOptions, for_display: bool) -> Stri
         } else {
             HashMap::new()
         };
-        output.push_str(&build_entry(path, opts, &git_statuses, for_display));
+        if passes_filters(path, opts, false) {
+            output.push_str(&build_entry(path, opts, &git_statuses, for_display));
+        }
         return output;
     }
 
-    let items = get_sorted_entries(path, opts.show_all);
+    let mut items = get_sorted_entries(path, opts.show_all);
     if items.is_empty() && !path.is_dir() {
         return format!("Error reading directory: {}\n", path.display());
     }
+    items.retain(|item| passes_filters(item, opts, false));
 
     let git_statuses = if opts.show_git {
         get_git_statuses(path)
@@ -219,6 +547,12 @@ pub fn build_list(path: &Path, opts: &DisplayOptions, for_display: bool) -> Stri
                 (false, false) => "",
             };
 
+            let age_header = if opts.show_note_age {
+                format!(" {:>4}", "Age")
+            } else {
+                String::new()
+            };
+
             if for_display {
                 // Colored headers - format width first, then colorize
                 let perms_h = format!("{:<10}", "Perms").magenta();
@@ -227,17 +561,33 @@ pub fn build_list(path: &Path, opts: &DisplayOptions, for_display: bool) -> Stri
                 let group_h = format!("{:<8}", "Group").yellow();
                 let size_h = size_header.green();
                 let modified_h = format!("{:>12}", "Modified").blue();
+                let age_h = age_header.bright_black();
                 let name_h = "Name".cyan();
 
                 let header = format!(
-                    "{} {} {} {} {} {} {}{}\n",
-                    perms_h, links_h, user_h, group_h, size_h, modified_h, extra_spacing, name_h
+                    "{} {} {} {} {} {}{} {}{}\n",
+                    perms_h,
+                    links_h,
+                    user_h,
+                    group_h,
+                    size_h,
+                    modified_h,
+                    age_h,
+                    extra_spacing,
+                    name_h
                 );
                 output.push_str(&header);
             } else {
                 let header = format!(
-                    "{:<10} {:>2} {:<8} {:<8} {} {:>12} {}Name\n",
-                    "Perms", "L", "User", "Group", size_header, "Modified", extra_spacing
+                    "{:<10} {:>2} {:<8} {:<8} {} {:>12}{} {}Name\n",
+                    "Perms",
+                    "L",
+                    "User",
+                    "Group",
+                    size_header,
+                    "Modified",
+                    age_header,
+                    extra_spacing
                 );
                 output.push_str(&header);
             }
@@ -248,6 +598,22 @@ pub fn build_list(path: &Path, opts: &DisplayOptions, for_display: bool) -> Stri
         output.push_str(&build_entry(&item, opts, &git_statuses, for_display));
     }
 
+    // Notes set ahead of time for paths that don't exist yet
+    let abs_dir = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    for (pending_path, note) in opts.notes.pending_in(&abs_dir) {
+        let name = pending_path
+            .file_name()
+            .map(|n| n.to_string_lossy())
+            .unwrap_or_default();
+        if !opts.show_all && name.starts_with('.') {
+            continue;
+        }
+        if !passes_note_filters(note, opts) {
+            continue;
+        }
+        output.push_str(&build_ghost_entry(&name, note, opts, for_display));
+    }
+
     output
 }
 
@@ -274,7 +640,8 @@ fn build_tree_recursive(
     output: &mut String,
     for_display: bool,
 ) {
-    let items = get_sorted_entries(path, opts.show_all);
+    let mut items = get_sorted_entries(path, opts.show_all);
+    items.retain(|item| passes_filters(item, opts, true));
 
     let git_statuses = if opts.show_git {
         get_git_statuses(path)
@@ -342,20 +709,27 @@ fn build_tree_recursive(
             String::new()
         };
 
-        let note = get_note(item);
-        let note_str = if let Some(n) = note {
-            if for_display {
-                format!("  {}", format!("# {}", n).bright_black())
-            } else {
-                format!("  # {}", n)
-            }
+        let shown = ShownNote::lookup(&abs_item, opts);
+        let note_str = shown
+            .as_ref()
+            .map(|shown| format_note(shown, for_display))
+            .unwrap_or_default();
+        // Directories show the progress of every checklist inside them
+        let progress = if metadata.is_dir() {
+            opts.notes.checklist_within(&abs_item)
         } else {
-            String::new()
+            note_progress(shown.as_ref())
         };
 
         output.push_str(&format!(
-            "{}{}{}{}{}{}\n",
-            prefix, connector, git_indicator, icon, display_name, note_str
+            "{}{}{}{}{}{}{}\n",
+            prefix,
+            connector,
+            git_indicator,
+            icon,
+            display_name,
+            format_progress(progress, for_display),
+            note_str
         ));
 
         // Recurse into directories
@@ -366,6 +740,68 @@ fn build_tree_recursive(
     }
 }
 
+/// Build search results as a String, one short-format entry per matching note.
+/// Paths are shown relative to `base` when they are inside it.
+pub fn build_search_results(
+    matches: &[(&Path, &Note)],
+    base: &Path,
+    opts: &DisplayOptions,
+    for_display: bool,
+) -> String {
+    let mut output = String::new();
+
+    for (path, note) in matches {
+        let name = path.strip_prefix(base).unwrap_or(path).to_string_lossy();
+        match fs::symlink_metadata(path) {
+            Ok(metadata) => output.push_str(&build_short_format(
+                &name,
+                &metadata,
+                Some(&ShownNote::own(note)),
+                opts,
+                None,
+                for_display,
+            )),
+            // The file is gone; there is no metadata to color or pick an icon by
+            Err(_) => output.push_str(&format!(
+                "{}{}\n",
+                name,
+                format_note(&ShownNote::own(note), for_display)
+            )),
+        }
+    }
+
+    output
+}
+
+/// Build the list of notes that are due: each note's expiry date, its path
+/// relative to `base`, and its text.
+pub fn build_due_list(due: &[(&Path, &Note)], base: &Path, for_display: bool) -> String {
+    let mut output = String::new();
+
+    for (path, note) in due {
+        let name = path.strip_prefix(base).unwrap_or(path).to_string_lossy();
+        let date = note
+            .expires
+            .and_then(|t| DateTime::from_timestamp(t, 0))
+            .map(|t| t.with_timezone(&Local).format("%Y-%m-%d").to_string())
+            .unwrap_or_default();
+        let date = if for_display && note.is_expired() {
+            date.color(parse_color(&get_config().colors.expired))
+                .to_string()
+        } else {
+            date
+        };
+        output.push_str(&format!(
+            "{}  {}{}\n",
+            date,
+            name,
+            format_note(&ShownNote::own(note), for_display)
+        ));
+    }
+
+    output
+}
+
 /// Build a single directory entry as a String.
 fn build_entry(
     path: &Path,
@@ -380,23 +816,30 @@ fn build_entry(
 
     let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("?");
 
-    let note = get_note(path);
-    // Use absolute path for git status lookup
+    // Use absolute path for git status and note lookup
     let abs_path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
     let git_status = git_statuses.get(&abs_path);
+    let note = ShownNote::lookup(&abs_path, opts);
 
     if opts.long_format {
         build_long_format(
             path,
             &metadata,
             file_name,
-            note,
+            note.as_ref(),
             opts,
             git_status,
             for_display,
         )
     } else {
-        build_short_format(file_name, &metadata, note, opts, git_status, for_display)
+        build_short_format(
+            file_name,
+            &metadata,
+            note.as_ref(),
+            opts,
+            git_status,
+            for_display,
+        )
     }
 }
 
@@ -405,7 +848,7 @@ fn build_long_format(
     path: &Path,
     metadata: &Metadata,
     name: &str,
-    note: Option<String>,
+    note: Option<&ShownNote>,
     opts: &DisplayOptions,
     git_status: Option<&GitStatus>,
     for_display: bool,
@@ -471,27 +914,84 @@ fn build_long_format(
         String::new()
     };
 
+    let age_str = if opts.show_note_age {
+        let age = format!(
+            " {:>4}",
+            note.filter(|n| !n.inherited)
+                .and_then(|n| n.note.updated)
+                .map(format_age)
+                .unwrap_or_default()
+        );
+        if for_display {
+            age.bright_black().to_string()
+        } else {
+            age
+        }
+    } else {
+        String::new()
+    };
+
     output.push_str(&format!(
-        "{} {:>2} {:<8} {:<8} {} {} {}{}{}{}",
+        "{} {:>2} {:<8} {:<8} {} {}{} {}{}{}{}{}",
         mode,
         nlink,
         user,
         group,
         size_str,
         date_str,
+        age_str,
         git_indicator,
         icon_prefix,
         display_name,
-        link_target
+        link_target,
+        format_progress(note_progress(note), for_display)
     ));
 
     if let Some(n) = note {
+        output.push_str(&format_note(n, for_display));
+    }
+    output.push('\n');
+
+    output
+}
+
+/// Build a ghost entry for a pending note on a path that doesn't exist yet,
+/// with placeholders in the long format columns.
+fn build_ghost_entry(name: &str, note: &Note, opts: &DisplayOptions, for_display: bool) -> String {
+    let mut output = String::new();
+
+    if opts.long_format {
+        let size_width = if opts.human_readable { 6 } else { 8 };
+        let age_str = if opts.show_note_age {
+            format!(" {:>4}", note.updated.map(format_age).unwrap_or_default())
+        } else {
+            String::new()
+        };
+        let columns = format!(
+            "{:<10} {:>2} {:<8} {:<8} {:>size_width$} {:>12}{} ",
+            "-", "-", "-", "-", "-", "-", age_str
+        );
         if for_display {
-            output.push_str(&format!("  {}", format!("# {}", n).bright_black()));
+            output.push_str(&columns.bright_black().to_string());
         } else {
-            output.push_str(&format!("  # {}", n));
+            output.push_str(&columns);
         }
     }
+    if opts.show_git {
+        output.push_str(&format!("{} ", format_git_status_ex(None, for_display)));
+    }
+    if opts.show_icons {
+        output.push_str(&format!("{} ", get_config().icons.pending));
+    }
+
+    if for_display {
+        output.push_str(&name.bright_black().italic().to_string());
+    } else {
+        output.push_str(name);
+    }
+    let shown = ShownNote::own(note);
+    output.push_str(&format_progress(note_progress(Some(&shown)), for_display));
+    output.push_str(&format_note(&shown, for_display));
     output.push('\n');
 
     output
@@ -501,7 +1001,7 @@ fn build_long_format(
 fn build_short_format(
     name: &str,
     metadata: &Metadata,
-    note: Option<String>,
+    note: Option<&ShownNote>,
     opts: &DisplayOptions,
     git_status: Option<&GitStatus>,
     for_display: bool,
@@ -528,13 +1028,10 @@ fn build_short_format(
         name.to_string()
     };
     output.push_str(&display_name);
+    output.push_str(&format_progress(note_progress(note), for_display));
 
     if let Some(n) = note {
-        if for_display {
-            output.push_str(&format!("  {}", format!("# {}", n).bright_black()));
-        } else {
-            output.push_str(&format!("  # {}", n));
-        }
+        output.push_str(&format_note(n, for_display));
     }
     output.push('\n');
 
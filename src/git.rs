@@ -1,10 +1,18 @@
 //! Git integration for lsnote.
 //!
-//! Provides git status detection and formatting for files and directories.
+//! Provides git status detection and formatting for files and directories,
+//! and reads and writes the git notes used by `notes.backend = git`.
+//!
+//! Git notes annotate objects, not paths, so each note is attached to the
+//! blob holding its repo-relative path. Every clone computes the same blob
+//! for the same path, so notes pushed and fetched on [`NOTES_REF`] find
+//! their files in any clone.
 
 use std::collections::HashMap;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
 
 use colored::Colorize;
 
@@ -31,6 +39,41 @@ fn git_status_priority(status: &GitStatus) -> u8 {
     }
 }
 
+/// Get the root of the git work tree containing `dir`, if any.
+pub fn get_git_root(dir: &Path) -> Option<PathBuf> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--show-toplevel"])
+        .current_dir(dir)
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8(output.stdout)
+        .ok()
+        .map(|s| PathBuf::from(s.trim()))
+}
+
+/// Get the configured git user name (`git config user.name`) for `dir`.
+pub fn get_git_user(dir: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .args(["config", "user.name"])
+        .current_dir(dir)
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8(output.stdout)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
 /// Get git statuses for all files in a directory.
 ///
 /// Returns a map from absolute paths to their git status.
@@ -41,25 +84,10 @@ pub fn get_git_statuses(dir: &Path) -> HashMap<PathBuf, GitStatus> {
     // Get absolute path of directory
     let abs_dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
 
-    // Check if we're in a git repo
-    let output = Command::new("git")
-        .args(["rev-parse", "--is-inside-work-tree"])
-        .current_dir(&abs_dir)
-        .output();
-
-    if output.is_err() || !output.unwrap().status.success() {
-        return statuses;
-    }
-
-    // Get the git root directory
-    let git_root = Command::new("git")
-        .args(["rev-parse", "--show-toplevel"])
-        .current_dir(&abs_dir)
-        .output()
-        .ok()
-        .and_then(|o| String::from_utf8(o.stdout).ok())
-        .map(|s| PathBuf::from(s.trim()))
-        .unwrap_or_else(|| abs_dir.clone());
+    let git_root = match get_git_root(&abs_dir) {
+        Some(root) => root,
+        None => return statuses,
+    };
 
     // Get status for all files (paths are relative to git root)
     if let Ok(output) = Command::new("git")
@@ -162,3 +190,121 @@ pub fn format_git_status_ex(status: Option<&GitStatus>, colored: bool) -> String
         Some(GitStatus::Clean) | None => " ".to_string(),
     }
 }
+
+/// Ref holding the notes written with `notes.backend = git`.
+pub const NOTES_REF: &str = "refs/notes/lsnote";
+
+/// Run git in `root`, feeding it `input`, and return its output if it
+/// succeeded.
+fn run_git(root: &Path, args: &[&str], input: &[u8]) -> Result<Vec<u8>, String> {
+    let mut child = Command::new("git")
+        .args(args)
+        .current_dir(root)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Could not run git: {}", e))?;
+    // Feed stdin from another thread: git may fill stdout before it has
+    // read all of its input (`cat-file --batch` flushes after each object)
+    let stdin = child.stdin.take();
+    let (written, output) = std::thread::scope(|scope| {
+        let writer = scope.spawn(move || match stdin {
+            Some(mut stdin) => stdin.write_all(input),
+            None => Ok(()),
+        });
+        let output = child.wait_with_output();
+        (writer.join(), output)
+    });
+    let output = output.map_err(|e| format!("Could not run git: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    written
+        .map_err(|_| "Could not run git: writing its input panicked".to_string())?
+        .map_err(|e| format!("Could not run git: {}", e))?;
+    Ok(output.stdout)
+}
+
+/// The object a repo-relative path's note is attached to. With `write`, the
+/// object is also stored so a note can be added to it.
+fn path_object(root: &Path, rel: &Path, write: bool) -> Result<String, String> {
+    let args: &[&str] = if write {
+        &["hash-object", "-w", "--stdin"]
+    } else {
+        &["hash-object", "--stdin"]
+    };
+    let oid = run_git(root, args, rel.as_os_str().as_bytes())?;
+    Ok(String::from_utf8_lossy(&oid).trim().to_string())
+}
+
+/// Read the git note for a repo-relative path.
+pub fn read_git_note(root: &Path, rel: &Path) -> Option<String> {
+    let oid = path_object(root, rel, false).ok()?;
+    let note = run_git(root, &["notes", "--ref", NOTES_REF, "show", &oid], b"").ok()?;
+    String::from_utf8(note).ok()
+}
+
+/// Read every git note on [`NOTES_REF`] in the repository at `root`.
+pub fn read_git_notes(root: &Path) -> Vec<String> {
+    let Ok(list) = run_git(root, &["notes", "--ref", NOTES_REF, "list"], b"") else {
+        return Vec::new();
+    };
+    // Each line is `<note blob> <annotated object>`; fetch all the blobs at once
+    let blobs: String = String::from_utf8_lossy(&list)
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(|blob| format!("{}\n", blob))
+        .collect();
+    if blobs.is_empty() {
+        return Vec::new();
+    }
+    let Ok(output) = run_git(root, &["cat-file", "--batch"], blobs.as_bytes()) else {
+        return Vec::new();
+    };
+
+    // `<oid> blob <size>\n<content>\n` per blob
+    let mut notes = Vec::new();
+    let mut rest = output.as_slice();
+    while let Some(newline) = rest.iter().position(|&b| b == b'\n') {
+        let header = String::from_utf8_lossy(&rest[..newline]).into_owned();
+        rest = &rest[newline + 1..];
+        let Some(size) = header
+            .split(' ')
+            .nth(2)
+            .and_then(|size| size.parse::<usize>().ok())
+        else {
+            continue;
+        };
+        let content = &rest[..size.min(rest.len())];
+        notes.push(String::from_utf8_lossy(content).into_owned());
+        rest = rest.get(size + 1..).unwrap_or_default();
+    }
+    notes
+}
+
+/// Set the git note for a repo-relative path, replacing any existing one.
+pub fn write_git_note(root: &Path, rel: &Path, content: &str) -> Result<(), String> {
+    let oid = path_object(root, rel, true)?;
+    run_git(
+        root,
+        &["notes", "--ref", NOTES_REF, "add", "-f", "-F", "-", &oid],
+        content.as_bytes(),
+    )
+    .map(|_| ())
+}
+
+/// Remove the git note for a repo-relative path. Returns whether there was
+/// one.
+pub fn remove_git_note(root: &Path, rel: &Path) -> Result<bool, String> {
+    if read_git_note(root, rel).is_none() {
+        return Ok(false);
+    }
+    let oid = path_object(root, rel, false)?;
+    run_git(root, &["notes", "--ref", NOTES_REF, "remove", &oid], b"")?;
+    Ok(true)
+}
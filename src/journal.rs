@@ -0,0 +1,441 @@
+//! Undo journal for note changes.
+//!
+//! Before any note is changed, its previous state is appended to
+//! `~/.lsnote/journal`, grouped by operation (one lsnote invocation), so
+//! `lsnote --undo` can put the notes touched by the last operations back.
+//!
+//! ```text
+//! # lsnote journal v1
+//! op<TAB>id<TAB>timestamp<TAB>command
+//! id<TAB>store<TAB>path<TAB>none
+//! id<TAB>store<TAB>path<TAB>note<TAB>fields
+//! ```
+//!
+//! `store` says where the note lives: `global`, `db` (the SQLite database),
+//! `xattr`, `project:<root>` or `git:<root>` (git notes in that repository). A `none` record means the path had no note;
+//! otherwise the fields are those of a notes file record. Paths, roots and
+//! commands are escaped like notes file fields.
+//!
+//! Note text given on the command line is recorded as `…` when the note is
+//! private, and [`seal_path`] encrypts a note's earlier states once it is
+//! made private.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use crate::config::Backend;
+use crate::get_data_dir;
+use crate::notes::{
+    escape, format_note_fields, parse_note_fields, unescape, Note, NotesFile, NotesLock,
+};
+use crate::private::NoteKey;
+use crate::storage::{lock_storage, restore_git, restore_xattr};
+
+const JOURNAL_FILE: &str = "journal";
+const JOURNAL_LOCK: &str = "journal.lock";
+const JOURNAL_HEADER: &str = "# lsnote journal v1";
+
+/// Operations kept when the journal is trimmed.
+const MAX_OPERATIONS: usize = 100;
+
+/// Size past which the journal is trimmed to [`MAX_OPERATIONS`].
+const MAX_JOURNAL_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Whether this process has written its operation line yet.
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Command-line arguments holding note text, and whether `--private` was
+/// given (see [`mark_note_text`]).
+static NOTE_TEXT: OnceLock<(Vec<OsString>, bool)> = OnceLock::new();
+
+/// What note text in a recorded command is replaced with.
+const REDACTED: &str = "…";
+
+/// Notes paired with the state to record or restore (`None` for no note).
+pub type NoteStates = Vec<(PathBuf, Option<Note>)>;
+
+/// Where a journaled note lives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Store {
+    /// The global notes file.
+    Global,
+    /// The global SQLite database.
+    Database,
+    /// An extended attribute on the file itself.
+    Xattr,
+    /// The notes file of the project at this root.
+    Project(PathBuf),
+    /// Git notes in the repository at this root.
+    Git(PathBuf),
+}
+
+impl Store {
+    fn format(&self) -> String {
+        match self {
+            Store::Global => "global".to_string(),
+            Store::Database => "db".to_string(),
+            Store::Xattr => "xattr".to_string(),
+            Store::Project(root) => format!("project:{}", escape(path_bytes(root))),
+            Store::Git(root) => format!("git:{}", escape(path_bytes(root))),
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "global" => Some(Store::Global),
+            "db" => Some(Store::Database),
+            "xattr" => Some(Store::Xattr),
+            _ => {
+                if let Some(root) = s.strip_prefix("git:") {
+                    return Some(Store::Git(path_from_bytes(unescape(root)?)));
+                }
+                let root = unescape(s.strip_prefix("project:")?)?;
+                Some(Store::Project(path_from_bytes(root)))
+            }
+        }
+    }
+
+    /// Put notes back to their recorded states, without journaling.
+    fn restore(&self, states: &[(PathBuf, Option<Note>)]) -> Result<(), String> {
+        let file = match self {
+            Store::Global => NotesFile::global_with(Backend::File)?,
+            Store::Database => NotesFile::global_with(Backend::Sqlite)?,
+            Store::Project(root) => NotesFile::project(root),
+            Store::Xattr => {
+                let _lock = lock_storage()?;
+                for (path, note) in states {
+                    restore_xattr(path, note.as_ref())?;
+                }
+                return Ok(());
+            }
+            Store::Git(root) => {
+                let _lock = lock_storage()?;
+                return restore_git(root, states);
+            }
+        };
+        file.restore(states)
+    }
+}
+
+/// One undoable operation read back from the journal.
+pub struct Operation {
+    id: String,
+    /// Unix timestamp of when the operation started.
+    pub time: i64,
+    /// The command line that made the change.
+    pub command: String,
+    /// Previous states in the order they were recorded.
+    changes: Vec<(Store, PathBuf, Option<Note>)>,
+}
+
+impl Operation {
+    /// Number of notes the operation touched.
+    pub fn note_count(&self) -> usize {
+        let mut paths: Vec<_> = self.changes.iter().map(|(_, path, _)| path).collect();
+        paths.sort();
+        paths.dedup();
+        paths.len()
+    }
+}
+
+fn path_bytes(path: &Path) -> &[u8] {
+    path.as_os_str().as_bytes()
+}
+
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+fn journal_path() -> Result<PathBuf, String> {
+    Ok(get_data_dir()?.join(JOURNAL_FILE))
+}
+
+/// Identifier shared by every change made by this process.
+fn operation_id() -> &'static str {
+    static ID: OnceLock<String> = OnceLock::new();
+    ID.get_or_init(|| {
+        let now = chrono::Utc::now();
+        format!(
+            "{}.{:09}-{}",
+            now.timestamp(),
+            now.timestamp_subsec_nanos(),
+            std::process::id()
+        )
+    })
+}
+
+/// Mark the command-line arguments that hold note text, so they can be left
+/// out of the recorded command when the note is or becomes private.
+pub fn mark_note_text(args: Vec<OsString>, private: bool) {
+    let _ = NOTE_TEXT.set((args, private));
+}
+
+/// Record the previous states of notes about to change in `store`.
+pub fn record(store: &Store, previous: &[(PathBuf, Option<Note>)]) -> Result<(), String> {
+    if previous.is_empty() {
+        return Ok(());
+    }
+    let path = journal_path()?;
+    let _lock = NotesLock::acquire(&path.with_file_name(JOURNAL_LOCK))?;
+
+    let mut out = String::new();
+    let is_new = fs::metadata(&path).map(|m| m.len() == 0).unwrap_or(true);
+    if is_new {
+        out.push_str(JOURNAL_HEADER);
+        out.push('\n');
+    }
+    let id = operation_id();
+    if !STARTED.swap(true, Ordering::SeqCst) {
+        let mut args = std::env::args_os();
+        let program = args
+            .next()
+            .map(PathBuf::from)
+            .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+            .unwrap_or_default();
+        // Keep the text of private notes out of the journal
+        let (note_text, private) = NOTE_TEXT
+            .get()
+            .map_or((&[][..], false), |(t, p)| (&t[..], *p));
+        let redact = private
+            || previous
+                .iter()
+                .any(|(_, note)| note.as_ref().is_some_and(|n| n.private));
+        let command: Vec<String> = std::iter::once(program)
+            .chain(args.map(|arg| {
+                if redact && note_text.contains(&arg) {
+                    REDACTED.to_string()
+                } else {
+                    arg.to_string_lossy().into_owned()
+                }
+            }))
+            .collect();
+        out.push_str(&format!(
+            "op\t{}\t{}\t{}\n",
+            id,
+            chrono::Utc::now().timestamp(),
+            escape(command.join(" ").as_bytes())
+        ));
+    }
+    for (note_path, note) in previous {
+        out.push_str(&format_change(id, store, note_path, note.as_ref()));
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(&path)
+        .map_err(|e| format!("Could not write undo journal: {}", e))?;
+    file.write_all(out.as_bytes())
+        .map_err(|e| format!("Could not write undo journal: {}", e))?;
+
+    if file.metadata().map(|m| m.len()).unwrap_or(0) > MAX_JOURNAL_SIZE {
+        let mut operations = read_operations(&path);
+        let excess = operations.len().saturating_sub(MAX_OPERATIONS);
+        operations.drain(..excess);
+        write_operations(&path, &operations)?;
+    }
+    Ok(())
+}
+
+/// Format a journal line recording the previous state of a note.
+fn format_change(id: &str, store: &Store, path: &Path, note: Option<&Note>) -> String {
+    let state = match note {
+        Some(note) => format!("note\t{}", format_note_fields(note)),
+        None => "none".to_string(),
+    };
+    format!(
+        "{}\t{}\t{}\t{}\n",
+        id,
+        store.format(),
+        escape(path_bytes(path)),
+        state
+    )
+}
+
+/// Record the notes that differ between `before` and `after` in `store`.
+pub fn record_diff(
+    store: &Store,
+    before: &HashMap<PathBuf, Note>,
+    after: &HashMap<PathBuf, Note>,
+) -> Result<(), String> {
+    let mut previous: Vec<_> = before
+        .keys()
+        .chain(after.keys().filter(|path| !before.contains_key(*path)))
+        .filter(|path| before.get(*path) != after.get(*path))
+        .map(|path| (path.clone(), before.get(path).cloned()))
+        .collect();
+    previous.sort_by(|a, b| a.0.cmp(&b.0));
+    record(store, &previous)
+}
+
+/// Read every operation in the journal, oldest first.
+fn read_operations(path: &Path) -> Vec<Operation> {
+    let content = fs::read(path).unwrap_or_default();
+    let content = String::from_utf8_lossy(&content);
+
+    let mut operations: Vec<Operation> = Vec::new();
+    for line in content.lines().filter(|l| !l.starts_with('#')) {
+        let fields: Vec<&str> = line.splitn(5, '\t').collect();
+        if fields[0] == "op" && fields.len() >= 4 {
+            let command = unescape(fields[3]).unwrap_or_default();
+            operations.push(Operation {
+                id: fields[1].to_string(),
+                time: fields[2].parse().unwrap_or(0),
+                command: String::from_utf8_lossy(&command).into_owned(),
+                changes: Vec::new(),
+            });
+            continue;
+        }
+        let Some(change) = parse_change(&fields) else {
+            continue;
+        };
+        if let Some(op) = operations.iter_mut().rev().find(|op| op.id == fields[0]) {
+            op.changes.push(change);
+        }
+    }
+    operations
+}
+
+fn parse_change(fields: &[&str]) -> Option<(Store, PathBuf, Option<Note>)> {
+    let store = Store::parse(fields.get(1)?)?;
+    let path = path_from_bytes(unescape(fields.get(2)?)?);
+    let note = match *fields.get(3)? {
+        "none" => None,
+        "note" => Some(parse_note_fields(fields.get(4)?)?),
+        _ => return None,
+    };
+    Some((store, path, note))
+}
+
+/// Rewrite the journal with just `operations`.
+fn write_operations(path: &Path, operations: &[Operation]) -> Result<(), String> {
+    let mut out = format!("{}\n", JOURNAL_HEADER);
+    for op in operations {
+        out.push_str(&format!(
+            "op\t{}\t{}\t{}\n",
+            op.id,
+            op.time,
+            escape(op.command.as_bytes())
+        ));
+        for (store, note_path, note) in &op.changes {
+            out.push_str(&format_change(&op.id, store, note_path, note.as_ref()));
+        }
+    }
+
+    let tmp_path = path.with_file_name(format!("{}.tmp.{}", JOURNAL_FILE, std::process::id()));
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .and_then(|mut file| file.write_all(out.as_bytes()))
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|e| format!("Could not write undo journal: {}", e))
+}
+
+/// Encrypt every plain-text state of `path` in the journal with `key`, once
+/// its note has been made private, and take the arguments out of the
+/// commands that changed it. Undoing those operations brings the note back
+/// private.
+pub fn seal_path(path: &Path, key: &NoteKey) -> Result<(), String> {
+    let journal = journal_path()?;
+    let _lock = NotesLock::acquire(&journal.with_file_name(JOURNAL_LOCK))?;
+
+    let mut operations = read_operations(&journal);
+    let mut sealed = false;
+    for op in &mut operations {
+        let mut touched = false;
+        for (_, note_path, note) in &mut op.changes {
+            if note_path != path {
+                continue;
+            }
+            touched = true;
+            if let Some(note) = note.as_mut().filter(|n| !n.private) {
+                // Private notes keep no history
+                note.history.clear();
+                key.seal(note)?;
+            }
+        }
+        if touched {
+            op.command = redact_arguments(&op.command);
+            sealed = true;
+        }
+    }
+    if sealed {
+        write_operations(&journal, &operations)?;
+    }
+    Ok(())
+}
+
+/// Replace everything but the program and its flags in a recorded command.
+fn redact_arguments(command: &str) -> String {
+    let mut words: Vec<&str> = Vec::new();
+    for (i, word) in command.split(' ').enumerate() {
+        let keep = i == 0 || word.starts_with('-');
+        let word = if keep { word } else { REDACTED };
+        if !(word == REDACTED && words.last() == Some(&REDACTED)) {
+            words.push(word);
+        }
+    }
+    words.join(" ")
+}
+
+/// Undo the last `count` operations, newest first, restoring every note
+/// they touched to its previous state. Returns the operations undone.
+pub fn undo(count: usize) -> Result<Vec<Operation>, String> {
+    let path = journal_path()?;
+    let lock_path = path.with_file_name(JOURNAL_LOCK);
+
+    // Take the operations off the journal before restoring them: restoring
+    // locks the notes files, and saving notes locks them before the journal,
+    // so holding the journal lock meanwhile could deadlock
+    let mut pending = {
+        let _lock = NotesLock::acquire(&lock_path)?;
+        let mut operations = read_operations(&path);
+        let pending = operations.split_off(operations.len().saturating_sub(count));
+        write_operations(&path, &operations)?;
+        pending
+    };
+
+    let mut undone = Vec::new();
+    while let Some(op) = pending.pop() {
+        // Restore in reverse so a note changed twice ends up in its
+        // earliest recorded state
+        let mut by_store: Vec<(Store, NoteStates)> = Vec::new();
+        for (store, note_path, note) in op.changes.iter().rev() {
+            match by_store.iter_mut().find(|(s, _)| s == store) {
+                Some((_, states)) => states.push((note_path.clone(), note.clone())),
+                None => by_store.push((store.clone(), vec![(note_path.clone(), note.clone())])),
+            }
+        }
+        let restored = by_store
+            .iter()
+            .try_for_each(|(store, states)| store.restore(states));
+        if let Err(e) = restored {
+            // Keep the journal in step with what was actually undone
+            pending.push(op);
+            let _lock = NotesLock::acquire(&lock_path)?;
+            let mut operations = read_operations(&path);
+            for op in pending {
+                let at = operations
+                    .iter()
+                    .position(|other| other.id > op.id)
+                    .unwrap_or(operations.len());
+                operations.insert(at, op);
+            }
+            write_operations(&path, &operations)?;
+            return Err(e);
+        }
+        undone.push(op);
+    }
+    Ok(undone)
+}
@@ -2,21 +2,42 @@
 //!
 //! A modern `ls` replacement with file notes, emoji icons, and git integration.
 
+mod checklist;
 mod config;
 mod display;
 mod git;
 mod icons;
+mod journal;
+mod markdown;
 mod notes;
+mod private;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod storage;
+mod sync;
+mod transfer;
 
+use std::ffi::OsString;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 use arboard::Clipboard;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use colored::Colorize;
 
-use config::init_config;
-use display::{build_tree, list_directory, print_tree, DisplayOptions};
-use notes::{get_note, remove_note, set_note};
+use config::{init_config, Backend};
+use display::{
+    build_due_list, build_note_view, build_search_results, build_tree, list_directory, print_tree,
+    DisplayOptions,
+};
+use notes::{
+    extend_note, find_orphans, get_note_details, get_pattern_note, migrate_notes, move_with_note,
+    note_scope, prune_notes, remove_note, remove_pattern_note, remove_slot, set_note,
+    set_notes_batch, set_pattern_note, update_text, Kind, NotesFile, NotesStore, Scope, SetOptions,
+};
+use private::NoteKey;
+use transfer::{export_notes, import_notes, parse_import, Format, Strategy};
 
 // Hidden argument for clipboard daemon mode
 const CLIPBOARD_DAEMON_ARG: &str = "--__clipboard_daemon__";
@@ -26,6 +47,9 @@ const CLIPBOARD_DAEMON_ARG: &str = "--__clipboard_daemon__";
 #[command(about = "ls with notes - list directory contents with file notes")]
 #[command(version)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Directory or file to list
     #[arg(default_value = ".")]
     path: PathBuf,
@@ -44,12 +68,88 @@ struct Args {
 
     /// Set note for a file
     #[arg(short = 's', long = "set", value_names = ["FILE", "NOTE"], num_args = 2)]
-    set: Option<Vec<String>>,
+    set: Option<Vec<OsString>>,
+
+    /// Add a line to the end of a file's note, creating it if needed
+    #[arg(long = "append", value_names = ["FILE", "TEXT"], num_args = 2)]
+    append: Option<Vec<OsString>>,
+
+    /// Add a line to the start of a file's note, creating it if needed
+    #[arg(long = "prepend", value_names = ["FILE", "TEXT"], num_args = 2, conflicts_with = "append")]
+    prepend: Option<Vec<OsString>>,
+
+    /// Text joining --append/--prepend text to the note (default: newline,
+    /// or notes.separator in the config)
+    #[arg(long = "separator", value_name = "SEP")]
+    separator: Option<String>,
+
+    /// Store the note in the project's shared .lsnote/notes (with -s or -r)
+    #[arg(long = "project", conflicts_with = "global")]
+    project: bool,
+
+    /// Store the note in the global ~/.lsnote/notes (default for -s)
+    #[arg(long = "global")]
+    global: bool,
+
+    /// Treat FILE as a glob pattern such as 'src/**/*.gen.rs', whose note is
+    /// shown on every matching file without a note of its own (with -s, -g or -r)
+    #[arg(long = "glob")]
+    glob: bool,
+
+    /// Also show a directory's note, dimmed, on everything inside it (with -s)
+    #[arg(long = "inherit", conflicts_with = "no_inherit")]
+    inherit: bool,
+
+    /// Stop showing a directory's note on everything inside it (with -s)
+    #[arg(long = "no-inherit")]
+    no_inherit: bool,
+
+    /// Allow setting a note for a path that doesn't exist yet, shown as a
+    /// ghost entry when listing its directory (with -s)
+    #[arg(long = "allow-missing", requires = "set")]
+    allow_missing: bool,
+
+    /// Encrypt the note; it shows as 🔒 until listed with --unlock (with -s)
+    #[arg(long = "private", conflicts_with_all = ["public", "project"])]
+    private: bool,
+
+    /// Store the note as plain text again (with -s)
+    #[arg(long = "public")]
+    public: bool,
+
+    /// Decrypt private notes, asking for the passphrase unless a key file is
+    /// configured or LSNOTE_PASSPHRASE is set
+    #[arg(long = "unlock")]
+    unlock: bool,
+
+    /// Mark the note stale from DATE (YYYY-MM-DD), or `never` to clear it (with -s)
+    #[arg(long = "expires", value_name = "DATE", requires = "set")]
+    expires: Option<String>,
+
+    /// Set notes from stdin, one `FILE<TAB>NOTE` record per line
+    #[arg(long = "set-batch")]
+    set_batch: bool,
+
+    /// With --set-batch, records are separated by NUL instead of newline
+    #[arg(short = '0', long = "null", requires = "set_batch")]
+    null: bool,
+
+    /// Edit a file's note in $VISUAL or $EDITOR (an empty note removes it)
+    #[arg(short = 'e', long = "edit", value_name = "FILE")]
+    edit: Option<PathBuf>,
 
     /// Get note for a file
     #[arg(short = 'g', long = "get")]
     get: Option<PathBuf>,
 
+    /// Print a file's full note, with Markdown formatting
+    #[arg(long = "show", value_name = "FILE")]
+    show: Option<PathBuf>,
+
+    /// Show a note's author, timestamps and previous versions
+    #[arg(long = "history", value_name = "FILE")]
+    history: Option<PathBuf>,
+
     /// Remove note from a file
     #[arg(short = 'r', long = "remove")]
     remove: Option<PathBuf>,
@@ -78,20 +178,197 @@ struct Args {
     #[arg(long = "init-config")]
     init_config: bool,
 
+    /// Show how long ago each note was changed (long format)
+    #[arg(long = "note-age")]
+    note_age: bool,
+
+    /// Only show entries whose note has this #tag
+    #[arg(long = "tag", value_name = "TAG")]
+    tag: Option<String>,
+
+    /// Which slot of the note -s, --append, --prepend, -e, -g and -r work on; when
+    /// listing, only show entries with that slot set
+    #[arg(long = "type", value_name = "TYPE", value_enum)]
+    kind: Option<Kind>,
+
+    /// Same as --type todo: work on the to-do, or only list open to-dos
+    #[arg(long = "todo", conflicts_with_all = ["kind", "warn"])]
+    todo: bool,
+
+    /// Same as --type warn: work on the warning, or only list warnings
+    #[arg(long = "warn", conflicts_with = "kind")]
+    warn: bool,
+
+    /// Remove notes for files that no longer exist (same as `notes gc`)
+    #[arg(long = "prune")]
+    prune: bool,
+
+    /// Undo the last N note changes (default 1)
+    #[arg(long = "undo", value_name = "N", num_args = 0..=1, default_missing_value = "1")]
+    undo: Option<usize>,
+
     /// Copy output to clipboard (use with -t for tree, -l for long format, etc.)
     #[arg(short = 'c', long = "copy")]
     copy: bool,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Move or rename a file, keeping its note attached
+    Mv {
+        /// File to move
+        src: PathBuf,
+        /// Destination path or directory
+        dst: PathBuf,
+    },
+    /// Search note text
+    Search {
+        /// Text to search for
+        pattern: String,
+        /// Only search notes for files inside this directory
+        dir: Option<PathBuf>,
+        /// Treat the pattern as a regular expression
+        #[arg(short = 'e', long = "regex")]
+        regex: bool,
+        /// Ignore case when matching
+        #[arg(short = 'i', long = "ignore-case")]
+        ignore_case: bool,
+    },
+    /// List all note tags with how many notes use them
+    Tags,
+    /// List, add, check and uncheck `[ ]` items in a file's note
+    Checklist {
+        #[command(subcommand)]
+        command: ChecklistCommand,
+    },
+    /// Manage the notes store
+    Notes {
+        #[command(subcommand)]
+        command: NotesCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ChecklistCommand {
+    /// List the items with their numbers
+    List {
+        /// File whose note holds the checklist
+        file: PathBuf,
+    },
+    /// Add an open item to the end of the note
+    Add {
+        /// File whose note holds the checklist
+        file: PathBuf,
+        /// Text of the item
+        item: String,
+    },
+    /// Mark an item done
+    Check {
+        /// File whose note holds the checklist
+        file: PathBuf,
+        /// Number of the item, as shown by `checklist list`
+        number: usize,
+    },
+    /// Mark a done item open again
+    Uncheck {
+        /// File whose note holds the checklist
+        file: PathBuf,
+        /// Number of the item, as shown by `checklist list`
+        number: usize,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum NotesCommand {
+    /// Remove notes for files that no longer exist
+    Gc {
+        /// Only report what would be removed
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+        /// Remove without asking for confirmation
+        #[arg(short = 'y', long = "yes")]
+        yes: bool,
+    },
+    /// Export notes as JSON or CSV
+    Export {
+        /// Output format
+        #[arg(short = 'f', long = "format", value_enum, default_value = "json")]
+        format: Format,
+        /// Write to a file instead of stdout
+        #[arg(short = 'o', long = "output", value_name = "FILE")]
+        output: Option<PathBuf>,
+        /// Export the project's notes instead of the global notes
+        #[arg(long = "project")]
+        project: bool,
+    },
+    /// Import notes from a JSON or CSV export
+    Import {
+        /// File to import
+        file: PathBuf,
+        /// Input format (guessed from the file extension by default)
+        #[arg(short = 'f', long = "format", value_enum)]
+        format: Option<Format>,
+        /// What to do when a file already has a note
+        #[arg(short = 's', long = "strategy", value_enum, default_value = "merge")]
+        strategy: Strategy,
+        /// Rewrite paths under FROM to be under TO
+        #[arg(long = "rebase", value_names = ["FROM", "TO"], num_args = 2)]
+        rebase: Option<Vec<PathBuf>>,
+        /// Import into the project's notes instead of the global notes
+        #[arg(long = "project")]
+        project: bool,
+    },
+    /// List notes that have expired or expire soon
+    Due {
+        /// Also list notes expiring within this many days
+        #[arg(long = "within", value_name = "DAYS", default_value = "7")]
+        within: u32,
+    },
+    /// Merge the global notes with those in a shared directory
+    Sync {
+        /// Shared directory (default: notes.sync_dir in the config)
+        dir: Option<PathBuf>,
+        /// Only report what would change
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+    },
+    /// Copy the global notes between the notes file and the SQLite database
+    Migrate {
+        /// Where to copy the notes to
+        #[arg(long = "to", value_enum, default_value = "sqlite")]
+        to: Backend,
+    },
+}
+
 fn main() {
     // Check for hidden clipboard daemon mode
-    let raw_args: Vec<String> = std::env::args().collect();
+    let raw_args: Vec<OsString> = std::env::args_os().collect();
     if raw_args.len() >= 2 && raw_args[1] == CLIPBOARD_DAEMON_ARG {
         run_clipboard_daemon();
         return;
     }
 
     let args = Args::parse();
+    journal::mark_note_text(note_text_args(&args), args.private);
+    let kind = args
+        .kind
+        .or(args.todo.then_some(Kind::Todo))
+        .or(args.warn.then_some(Kind::Warn));
+
+    if let Some(command) = &args.command {
+        run_command(command, &args);
+        return;
+    }
+
+    if args.prune {
+        collect_garbage(false, false);
+        return;
+    }
+
+    if let Some(count) = args.undo {
+        undo(count);
+        return;
+    }
 
     // Handle init-config
     if args.init_config {
@@ -104,21 +381,158 @@ fn main() {
 
     // Handle set note
     if let Some(set_args) = &args.set {
-        let file = &set_args[0];
-        let note = &set_args[1];
-        let path = Path::new(file);
+        let path = Path::new(&set_args[0]);
+        let Some(note) = set_args[1].to_str() else {
+            eprintln!("Error setting note: note text is not valid UTF-8");
+            return;
+        };
 
-        match set_note(path, note) {
-            Ok(_) => println!("Note set for '{}'", file),
+        let scope = if args.project {
+            Scope::Project
+        } else {
+            Scope::Global
+        };
+        let result = if args.glob && kind.is_some_and(|k| k != Kind::Note) {
+            Err("pattern notes only have text".to_string())
+        } else if args.glob {
+            match set_args[0].to_str() {
+                Some(pattern) => set_pattern_note(pattern, note, scope),
+                None => Err("pattern is not valid UTF-8".to_string()),
+            }
+        } else {
+            let inherit = if args.inherit {
+                Some(true)
+            } else if args.no_inherit {
+                Some(false)
+            } else {
+                None
+            };
+            args.expires
+                .as_deref()
+                .map(parse_expiry)
+                .transpose()
+                .and_then(|expires| {
+                    let options = SetOptions {
+                        kind: kind.unwrap_or_default(),
+                        inherit,
+                        allow_missing: args.allow_missing,
+                        expires,
+                        private: if args.private {
+                            Some(true)
+                        } else if args.public {
+                            Some(false)
+                        } else {
+                            None
+                        },
+                    };
+                    set_note(path, note, scope, options)
+                })
+        };
+        match result {
+            Ok(_) => println!("Note set for '{}'", path.display()),
             Err(e) => eprintln!("Error setting note: {}", e),
         }
         return;
     }
 
+    // Handle append / prepend
+    if let Some((extend_args, prepend)) = args
+        .append
+        .as_ref()
+        .map(|a| (a, false))
+        .or(args.prepend.as_ref().map(|a| (a, true)))
+    {
+        let path = Path::new(&extend_args[0]);
+        let Some(text) = extend_args[1].to_str() else {
+            eprintln!("Error updating note: text is not valid UTF-8");
+            return;
+        };
+
+        let scope = if args.project {
+            Some(Scope::Project)
+        } else if args.global {
+            Some(Scope::Global)
+        } else {
+            note_scope(path)
+        };
+        let separator = args
+            .separator
+            .clone()
+            .unwrap_or_else(|| config::get_config().notes.separator.clone());
+        match extend_note(
+            path,
+            text,
+            scope.unwrap_or(Scope::Global),
+            kind.unwrap_or_default(),
+            prepend,
+            &separator,
+        ) {
+            Ok(_) => println!("Note updated for '{}'", path.display()),
+            Err(e) => eprintln!("Error updating note: {}", e),
+        }
+        return;
+    }
+
+    // Handle batch set from stdin
+    if args.set_batch {
+        let scope = if args.project {
+            Scope::Project
+        } else {
+            Scope::Global
+        };
+        set_batch(args.null, scope);
+        return;
+    }
+
+    // Handle edit note
+    if let Some(file) = &args.edit {
+        let scope = if args.project {
+            Some(Scope::Project)
+        } else if args.global {
+            Some(Scope::Global)
+        } else {
+            None
+        };
+        edit_note(file, scope, kind.unwrap_or_default());
+        return;
+    }
+
     // Handle get note
     if let Some(file) = &args.get {
-        match get_note(file) {
-            Some(note) => println!("{}", note),
+        if args.glob {
+            match get_pattern_note(&file.to_string_lossy()) {
+                Some(note) => println!("{}", note.text),
+                None => println!("No note set for '{}'", file.display()),
+            }
+            return;
+        }
+        let kind = kind.unwrap_or_default();
+        match get_note_details(file)
+            .filter(|note| note.slot(kind).is_some())
+            .map(|note| readable(note, args.unlock))
+        {
+            Some(Ok(note)) => println!("{}", note.slot(kind).unwrap_or_default()),
+            Some(Err(e)) => eprintln!("Error reading note: {}", e),
+            None => println!("No {} set for '{}'", kind.name(), file.display()),
+        }
+        return;
+    }
+
+    // Handle show note
+    if let Some(file) = &args.show {
+        match get_note_details(file).map(|note| readable(note, args.unlock)) {
+            Some(Ok(note)) => print!("{}", build_note_view(&note, true)),
+            Some(Err(e)) => eprintln!("Error reading note: {}", e),
+            None => println!("No note set for '{}'", file.display()),
+        }
+        return;
+    }
+
+    // Handle note history
+    if let Some(file) = &args.history {
+        match get_note_details(file).map(|note| readable(note, args.unlock)) {
+            Some(Ok(note)) => print_history(&note),
+            Some(Err(e)) => eprintln!("Error reading note: {}", e),
             None => println!("No note set for '{}'", file.display()),
         }
         return;
@@ -126,8 +540,26 @@ fn main() {
 
     // Handle remove note
     if let Some(file) = &args.remove {
-        match remove_note(file) {
-            Ok(_) => println!("Note removed from '{}'", file.display()),
+        let scope = if args.project {
+            Some(Scope::Project)
+        } else if args.global {
+            Some(Scope::Global)
+        } else {
+            None
+        };
+        let result = match kind {
+            Some(kind) if args.glob && kind != Kind::Note => {
+                Err("pattern notes only have text".to_string())
+            }
+            _ if args.glob => remove_pattern_note(&file.to_string_lossy(), scope),
+            Some(kind) => remove_slot(file, kind, scope),
+            None => remove_note(file, scope),
+        };
+        match result {
+            Ok(_) => match kind {
+                Some(kind) => println!("Removed the {} from '{}'", kind.name(), file.display()),
+                None => println!("Note removed from '{}'", file.display()),
+            },
             Err(e) => eprintln!("Error removing note: {}", e),
         }
         return;
@@ -137,6 +569,18 @@ fn main() {
     let show_icons = !args.no_icons;
     // -l overrides -S if both specified; long format is default
     let long_format = args.long || !args.short;
+    let key = if args.unlock {
+        match NoteKey::unlock() {
+            Ok(key) => Some(key),
+            Err(e) => {
+                eprintln!("Error unlocking private notes: {}", e);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
     let opts = DisplayOptions {
         show_all: args.all,
         long_format,
@@ -145,6 +589,11 @@ fn main() {
         show_git: !args.no_git,
         tree_view: args.tree,
         show_header: !args.no_header,
+        notes: Rc::new(NotesStore::load_for(&args.path)),
+        tag: args.tag.clone(),
+        kind,
+        show_note_age: args.note_age,
+        key,
     };
 
     if args.copy {
@@ -173,6 +622,637 @@ fn main() {
     }
 }
 
+/// Read `FILE<TAB>NOTE` records from stdin and set them all at once.
+fn set_batch(null_separated: bool, scope: Scope) {
+    use std::io::Read;
+    use std::os::unix::ffi::OsStringExt;
+
+    let mut input = Vec::new();
+    if let Err(e) = std::io::stdin().read_to_end(&mut input) {
+        eprintln!("Error reading stdin: {}", e);
+        return;
+    }
+
+    let (separator, unit) = if null_separated {
+        (b'\0', "record")
+    } else {
+        (b'\n', "line")
+    };
+    let mut records = Vec::new();
+    let mut failed = 0;
+    for (i, record) in input.split(|&b| b == separator).enumerate() {
+        let number = i + 1;
+        let record = record.strip_suffix(b"\r").unwrap_or(record);
+        if record.is_empty() {
+            continue;
+        }
+
+        let Some(tab) = record.iter().position(|&b| b == b'\t') else {
+            eprintln!("{} {}: expected FILE<TAB>NOTE", unit, number);
+            failed += 1;
+            continue;
+        };
+        let path = PathBuf::from(OsString::from_vec(record[..tab].to_vec()));
+        match String::from_utf8(record[tab + 1..].to_vec()) {
+            Ok(note) if !note.is_empty() => records.push((number, path, note)),
+            Ok(_) => {
+                eprintln!("{} {}: empty note for '{}'", unit, number, path.display());
+                failed += 1;
+            }
+            Err(_) => {
+                eprintln!("{} {}: note text is not valid UTF-8", unit, number);
+                failed += 1;
+            }
+        }
+    }
+
+    match set_notes_batch(records, scope) {
+        Ok((count, errors)) => {
+            for (number, error) in &errors {
+                eprintln!("{} {}: {}", unit, number, error);
+            }
+            println!("Set {} note(s), {} failed", count, failed + errors.len());
+        }
+        Err(e) => eprintln!("Error setting notes: {}", e),
+    }
+}
+
+/// Edit a slot of a note in the user's editor, saving it to `scope` (by
+/// default where the note already lives, or the global notes for a new note).
+fn edit_note(file: &Path, scope: Option<Scope>, kind: Kind) {
+    if let Err(e) = file.canonicalize() {
+        eprintln!("Error editing note: Could not resolve path: {}", e);
+        return;
+    }
+
+    let current = match get_note_details(file).map(|note| readable(note, true)) {
+        Some(Ok(note)) => note.slot(kind).unwrap_or_default().to_string(),
+        Some(Err(e)) => {
+            eprintln!("Error editing note: {}", e);
+            return;
+        }
+        None => String::new(),
+    };
+    let edited = match edit_in_editor(&current) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("Error editing note: {}", e);
+            return;
+        }
+    };
+    let edited = edited.trim_end();
+
+    if edited == current.trim_end() {
+        println!("Note unchanged for '{}'", file.display());
+        return;
+    }
+
+    let scope = scope.or_else(|| note_scope(file));
+    if edited.trim().is_empty() {
+        match remove_slot(file, kind, scope) {
+            Ok(_) => println!("Removed the {} from '{}'", kind.name(), file.display()),
+            Err(e) => eprintln!("Error removing note: {}", e),
+        }
+        return;
+    }
+
+    match set_note(
+        file,
+        edited,
+        scope.unwrap_or(Scope::Global),
+        SetOptions {
+            kind,
+            ..SetOptions::default()
+        },
+    ) {
+        Ok(_) => println!("Note set for '{}'", file.display()),
+        Err(e) => eprintln!("Error setting note: {}", e),
+    }
+}
+
+/// Open `text` in $VISUAL or $EDITOR (falling back to `vi`) via a private
+/// temporary file and return the edited contents.
+fn edit_in_editor(text: &str) -> Result<String, String> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::process::Command;
+
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .ok()
+        .filter(|e| !e.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string());
+
+    let tmp_path = std::env::temp_dir().join(format!("lsnote-{}.txt", std::process::id()));
+    let mut tmp = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .map_err(|e| format!("Could not create temporary file: {}", e))?;
+    let result = tmp
+        .write_all(text.as_bytes())
+        .map_err(|e| e.to_string())
+        .and_then(|_| {
+            // Run through the shell so editors with arguments ("code -w") work
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(format!("{} \"$1\"", editor))
+                .arg("sh")
+                .arg(&tmp_path)
+                .status()
+                .map_err(|e| format!("Could not start editor: {}", e))?;
+            if !status.success() {
+                return Err(format!("Editor '{}' exited with {}", editor, status));
+            }
+            fs::read_to_string(&tmp_path).map_err(|e| e.to_string())
+        });
+
+    let _ = fs::remove_file(&tmp_path);
+    result
+}
+
+/// The arguments holding note text, for the undo journal to leave out of
+/// the commands it records for private notes.
+fn note_text_args(args: &Args) -> Vec<OsString> {
+    let mut texts: Vec<OsString> = [&args.set, &args.append, &args.prepend]
+        .into_iter()
+        .filter_map(|values| values.as_ref()?.get(1).cloned())
+        .collect();
+    if let Some(Command::Checklist {
+        command: ChecklistCommand::Add { item, .. },
+    }) = &args.command
+    {
+        texts.push(OsString::from(item));
+    }
+    texts
+}
+
+/// Decrypt a private note if `unlock` is set, or refuse to show it.
+fn readable(note: notes::Note, unlock: bool) -> Result<notes::Note, String> {
+    if !note.private {
+        return Ok(note);
+    }
+    if !unlock {
+        return Err("the note is private; add --unlock to read it".to_string());
+    }
+    NoteKey::unlock()?.reveal(&note)
+}
+
+/// Print a note with its metadata, followed by its previous versions.
+fn print_history(note: &notes::Note) {
+    print_revision(note.updated, note.author.as_deref(), &note.text, "current");
+    if let Some(created) = note.created {
+        println!(
+            "{}",
+            format!("  created {}", format_timestamp(created)).bright_black()
+        );
+    }
+    if let Some(expires) = note.expires {
+        let line = format!("  expires {}", format_timestamp(expires));
+        if note.is_expired() {
+            let color = config::parse_color(&config::get_config().colors.expired);
+            println!("{}", line.color(color));
+        } else {
+            println!("{}", line.bright_black());
+        }
+    }
+    for revision in note.history.iter().rev() {
+        println!();
+        print_revision(
+            revision.updated,
+            revision.author.as_deref(),
+            &revision.text,
+            "",
+        );
+    }
+}
+
+/// Print one version of a note: a dimmed heading and the indented text.
+fn print_revision(updated: Option<i64>, author: Option<&str>, text: &str, label: &str) {
+    let mut heading = updated
+        .map(format_timestamp)
+        .unwrap_or_else(|| "unknown date".into());
+    if let Some(author) = author {
+        heading.push_str(&format!("  {}", author));
+    }
+    if !label.is_empty() {
+        heading.push_str(&format!("  ({})", label));
+    }
+    println!("{}", heading.bright_black());
+    for line in text.lines() {
+        println!("  {}", line);
+    }
+}
+
+/// Format a Unix timestamp in local time.
+fn format_timestamp(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|t| {
+            t.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+/// Run a subcommand.
+fn run_command(command: &Command, args: &Args) {
+    match command {
+        Command::Mv { src, dst } => match move_with_note(src, dst) {
+            Ok(dst) => println!("Moved '{}' to '{}'", src.display(), dst.display()),
+            Err(e) => eprintln!("Error moving file: {}", e),
+        },
+        Command::Search {
+            pattern,
+            dir,
+            regex,
+            ignore_case,
+        } => search(pattern, dir.as_deref(), *regex, *ignore_case, args),
+        Command::Tags => list_tags(),
+        Command::Checklist { command } => run_checklist(command, args),
+        Command::Notes { command } => match command {
+            NotesCommand::Gc { dry_run, yes } => collect_garbage(*dry_run, *yes),
+            NotesCommand::Export {
+                format,
+                output,
+                project,
+            } => export(*format, output.as_deref(), *project),
+            NotesCommand::Import {
+                file,
+                format,
+                strategy,
+                rebase,
+                project,
+            } => import(file, *format, *strategy, rebase.as_deref(), *project),
+            NotesCommand::Migrate { to } => migrate(*to),
+            NotesCommand::Due { within } => list_due(*within),
+            NotesCommand::Sync { dir, dry_run } => sync_notes(dir.as_deref(), *dry_run),
+        },
+    }
+}
+
+/// Run a `checklist` subcommand, changing the note where it already lives
+/// unless --project or --global says otherwise.
+fn run_checklist(command: &ChecklistCommand, args: &Args) {
+    let file = match command {
+        ChecklistCommand::List { file }
+        | ChecklistCommand::Add { file, .. }
+        | ChecklistCommand::Check { file, .. }
+        | ChecklistCommand::Uncheck { file, .. } => file,
+    };
+    let scope = if args.project {
+        Some(Scope::Project)
+    } else if args.global {
+        Some(Scope::Global)
+    } else {
+        note_scope(file)
+    }
+    .unwrap_or(Scope::Global);
+
+    let result = match command {
+        ChecklistCommand::List { .. } => {
+            print_checklist(file, args.unlock);
+            return;
+        }
+        ChecklistCommand::Add { item, .. } => {
+            update_text(file, scope, |text| Ok(checklist::add_item(text, item)))
+        }
+        ChecklistCommand::Check { number, .. } => {
+            update_text(file, scope, |text| checklist::set_done(text, *number, true))
+        }
+        ChecklistCommand::Uncheck { number, .. } => update_text(file, scope, |text| {
+            checklist::set_done(text, *number, false)
+        }),
+    };
+    match result {
+        Ok(_) => print_checklist(file, true),
+        Err(e) => eprintln!("Error updating checklist: {}", e),
+    }
+}
+
+/// Print the numbered checklist items in a file's note and how many are done.
+fn print_checklist(file: &Path, unlock: bool) {
+    let note = match get_note_details(file).map(|note| readable(note, unlock)) {
+        Some(Ok(note)) => note,
+        Some(Err(e)) => {
+            eprintln!("Error reading note: {}", e);
+            return;
+        }
+        None => {
+            println!("No note set for '{}'", file.display());
+            return;
+        }
+    };
+    let items = checklist::items(&note.text);
+    if items.is_empty() {
+        println!("No checklist in the note for '{}'", file.display());
+        return;
+    }
+
+    let done = items.iter().filter(|item| item.done).count();
+    println!(
+        "{}",
+        format!("{}: {}/{} done", file.display(), done, items.len()).bright_black()
+    );
+    for (i, item) in items.iter().enumerate() {
+        let mark = if item.done { "[x]" } else { "[ ]" };
+        let line = format!("{:>3} {} {}", i + 1, mark, item.text);
+        if item.done {
+            println!("{}", line.bright_black());
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Print notes matching a substring or regex, optionally within a directory.
+fn search(pattern: &str, dir: Option<&Path>, is_regex: bool, ignore_case: bool, args: &Args) {
+    let source = if is_regex {
+        pattern.to_string()
+    } else {
+        regex::escape(pattern)
+    };
+    let matcher = match regex::RegexBuilder::new(&source)
+        .case_insensitive(ignore_case)
+        .build()
+    {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("Invalid pattern: {}", e);
+            return;
+        }
+    };
+
+    let dir = match dir.map(Path::canonicalize).transpose() {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("Could not resolve path: {}", e);
+            return;
+        }
+    };
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let store = match &dir {
+        Some(dir) => NotesStore::load_for(dir),
+        None => NotesStore::load_all(&cwd),
+    };
+    let matches = store.search(&matcher, dir.as_deref());
+    if matches.is_empty() {
+        println!("No matching notes");
+        return;
+    }
+
+    let opts = DisplayOptions {
+        show_all: true,
+        long_format: false,
+        show_icons: !args.no_icons,
+        human_readable: !args.bytes,
+        show_git: false,
+        tree_view: false,
+        show_header: false,
+        notes: Rc::new(NotesStore::default()),
+        tag: None,
+        kind: None,
+        show_note_age: false,
+        key: None,
+    };
+    print!("{}", build_search_results(&matches, &cwd, &opts, true));
+}
+
+/// Print every tag in use with its note count.
+fn list_tags() {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let counts = NotesStore::load_all(&cwd).tag_counts();
+    if counts.is_empty() {
+        println!("No tags");
+        return;
+    }
+
+    let tag_color = config::parse_color(&config::get_config().colors.tag);
+    for (tag, count) in counts {
+        println!("{:>5}  {}", count, format!("#{}", tag).color(tag_color));
+    }
+}
+
+/// The global notes file, or the project's if `project` is set.
+fn target_notes_file(project: bool) -> Result<NotesFile, String> {
+    let scope = if project {
+        Scope::Project
+    } else {
+        Scope::Global
+    };
+    let cwd = std::env::current_dir().map_err(|e| e.to_string())?;
+    NotesFile::for_scope(scope, &cwd)
+}
+
+/// Export notes to stdout or a file.
+fn export(format: Format, output: Option<&Path>, project: bool) {
+    let file = match target_notes_file(project) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Error exporting notes: {}", e);
+            return;
+        }
+    };
+
+    let (data, skipped) = export_notes(&file, format);
+    for path in skipped {
+        eprintln!("Skipping non-UTF-8 path: {}", path.display());
+    }
+
+    match output {
+        Some(path) => match fs::write(path, data) {
+            Ok(_) => println!("Notes exported to '{}'", path.display()),
+            Err(e) => eprintln!("Error exporting notes: {}", e),
+        },
+        None => print!("{}", data),
+    }
+}
+
+/// Import notes from an export file.
+fn import(
+    path: &Path,
+    format: Option<Format>,
+    strategy: Strategy,
+    rebase: Option<&[PathBuf]>,
+    project: bool,
+) {
+    let result = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read '{}': {}", path.display(), e))
+        .and_then(|input| parse_import(&input, format.unwrap_or_else(|| Format::from_path(path))))
+        .and_then(|imported| {
+            let file = target_notes_file(project)?;
+            let rebase = rebase.map(|r| (r[0].as_path(), r[1].as_path()));
+            import_notes(&file, imported, strategy, rebase)
+        });
+
+    match result {
+        Ok(summary) => println!(
+            "Imported notes: {} added, {} updated, {} skipped",
+            summary.added, summary.updated, summary.skipped
+        ),
+        Err(e) => eprintln!("Error importing notes: {}", e),
+    }
+}
+
+/// Undo the last `count` note changes, saying what each one was.
+fn undo(count: usize) {
+    match journal::undo(count) {
+        Ok(undone) if undone.is_empty() => println!("Nothing to undo"),
+        Ok(undone) => {
+            for op in undone {
+                println!(
+                    "Undid `{}` from {} ({} note(s))",
+                    op.command,
+                    format_timestamp(op.time),
+                    op.note_count()
+                );
+            }
+        }
+        Err(e) => eprintln!("Error undoing: {}", e),
+    }
+}
+
+/// Print notes that have expired or expire within `within` days, soonest
+/// first.
+fn list_due(within: u32) {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let store = NotesStore::load_all(&cwd);
+    let until = chrono::Utc::now().timestamp() + i64::from(within) * 86400;
+    let due = store.due(until);
+    if due.is_empty() {
+        println!("No notes due");
+        return;
+    }
+    print!("{}", build_due_list(&due, &cwd, true));
+}
+
+/// Sync the global notes with a shared directory and report the changes.
+fn sync_notes(dir: Option<&Path>, dry_run: bool) {
+    let config = config::get_config();
+    let Some(dir) = dir.or(config.notes.sync_dir.as_deref()) else {
+        eprintln!("Error syncing notes: no directory given and notes.sync_dir is not set");
+        return;
+    };
+    let report = match sync::sync(dir, dry_run) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Error syncing notes: {}", e);
+            return;
+        }
+    };
+
+    for conflict in &report.conflicts {
+        let kept = if conflict.kept_local {
+            "kept this machine's version"
+        } else {
+            "took the shared version"
+        };
+        println!(
+            "{} {} ({}, the other is in its history)",
+            "conflict:".color(config::parse_color(&config.colors.expired)),
+            conflict.path.display(),
+            kept
+        );
+    }
+    println!(
+        "{} '{}': {} pulled, {} pushed, {} deleted, {} conflict(s)",
+        if dry_run {
+            "Would sync with"
+        } else {
+            "Synced with"
+        },
+        dir.display(),
+        report.pulled,
+        report.pushed,
+        report.deleted,
+        report.conflicts.len()
+    );
+    if report.skipped_private > 0 {
+        println!(
+            "{} private note(s) not synced: the shared key in '{}' differs from ~/.lsnote/private",
+            report.skipped_private,
+            dir.display()
+        );
+    }
+}
+
+/// Parse an `--expires` value: a `YYYY-MM-DD` date, taken as the start of
+/// that day in local time, or `never` for no expiry.
+fn parse_expiry(value: &str) -> Result<Option<i64>, String> {
+    if value.eq_ignore_ascii_case("never") {
+        return Ok(None);
+    }
+    chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .and_then(|t| t.and_local_timezone(chrono::Local).earliest())
+        .map(|t| Some(t.timestamp()))
+        .ok_or_else(|| format!("invalid expiry date '{}' (expected YYYY-MM-DD)", value))
+}
+
+/// Copy the global notes into the store used by `to`.
+fn migrate(to: Backend) {
+    match migrate_notes(to) {
+        Ok((count, path)) => {
+            println!("Migrated {} note(s) to '{}'", count, path.display());
+            if config::get_config().notes.backend != to {
+                let name = if to == Backend::Sqlite {
+                    "sqlite"
+                } else {
+                    "file"
+                };
+                println!(
+                    "Set 'notes.backend = {}' in ~/.lsnote/config to use them",
+                    name
+                );
+            }
+        }
+        Err(e) => eprintln!("Error migrating notes: {}", e),
+    }
+}
+
+/// List orphaned notes and remove them, asking first unless `yes` is set.
+fn collect_garbage(dry_run: bool, yes: bool) {
+    let orphans = find_orphans(Path::new("."));
+    if orphans.notes.is_empty() {
+        println!("No orphaned notes");
+        return;
+    }
+
+    for (path, note) in &orphans.notes {
+        let text = note.text.lines().next().unwrap_or("");
+        println!("{}  # {}", path.display(), text);
+    }
+
+    if dry_run {
+        println!("Would remove {} orphaned note(s)", orphans.notes.len());
+        return;
+    }
+    if !yes && !confirm(&format!("Remove {} orphaned note(s)?", orphans.notes.len())) {
+        println!("Nothing removed");
+        return;
+    }
+
+    match prune_notes(Path::new("."), &orphans) {
+        Ok(n) => println!("Removed {} orphaned note(s)", n),
+        Err(e) => eprintln!("Error removing notes: {}", e),
+    }
+}
+
+/// Ask a yes/no question on stderr and read the answer from stdin.
+fn confirm(question: &str) -> bool {
+    use std::io::Write;
+
+    eprint!("{} [y/N] ", question);
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
 /// Get the data directory: ~/.lsnote/
 pub fn get_data_dir() -> Result<PathBuf, String> {
     let data_dir = dirs::home_dir()
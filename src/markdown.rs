@@ -0,0 +1,204 @@
+//! Inline Markdown in note text.
+//!
+//! Only the inline syntax that reads well on a single listing line is
+//! recognized: `` `code` ``, `**bold**` and `*emphasis*` (also written with
+//! `_`), `[links](url)` and bare `http://` / `https://` URLs. Everything
+//! else, including unmatched markers, is plain text.
+
+/// How a run of note text is styled.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Style {
+    pub code: bool,
+    pub bold: bool,
+    pub italic: bool,
+    /// Target of the link the text belongs to.
+    pub link: Option<String>,
+}
+
+/// A run of note text with a single style, markers removed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub text: String,
+    pub style: Style,
+}
+
+/// Split note text into styled spans.
+pub fn parse_inline(text: &str) -> Vec<Span> {
+    let mut spans = Vec::new();
+    parse_into(text, &Style::default(), &mut spans);
+    spans
+}
+
+fn parse_into(text: &str, style: &Style, spans: &mut Vec<Span>) {
+    let mut plain_start = 0;
+    let mut i = 0;
+    while i < text.len() {
+        let before = text[..i].chars().next_back();
+        let rest = &text[i..];
+
+        let matched = code_span(rest, style)
+            .or_else(|| link(rest, style))
+            .or_else(|| bare_url(rest, before, style))
+            .or_else(|| emphasis(rest, before, style));
+        let Some((len, inner)) = matched else {
+            i += rest.chars().next().map_or(1, char::len_utf8);
+            continue;
+        };
+
+        push_plain(&text[plain_start..i], style, spans);
+        match inner {
+            Inner::Text(span) => spans.push(span),
+            Inner::Nested(content, nested) => parse_into(content, &nested, spans),
+        }
+        i += len;
+        plain_start = i;
+    }
+    push_plain(&text[plain_start..], style, spans);
+}
+
+/// What a matched construct contains.
+enum Inner<'a> {
+    /// Literal text, not parsed any further.
+    Text(Span),
+    /// Text that may contain more markup, with the style it adds.
+    Nested(&'a str, Style),
+}
+
+fn push_plain(text: &str, style: &Style, spans: &mut Vec<Span>) {
+    if !text.is_empty() {
+        spans.push(Span {
+            text: text.to_string(),
+            style: style.clone(),
+        });
+    }
+}
+
+/// `` `code` ``, whose content is shown as is.
+fn code_span<'a>(rest: &'a str, style: &Style) -> Option<(usize, Inner<'a>)> {
+    let content = rest.strip_prefix('`')?;
+    let end = content.find('`').filter(|&end| end > 0)?;
+    let span = Span {
+        text: content[..end].to_string(),
+        style: Style {
+            code: true,
+            ..style.clone()
+        },
+    };
+    Some((end + 2, Inner::Text(span)))
+}
+
+/// `[label](url)`.
+fn link<'a>(rest: &'a str, style: &Style) -> Option<(usize, Inner<'a>)> {
+    if style.link.is_some() {
+        return None;
+    }
+    let after = rest.strip_prefix('[')?;
+    let label_end = after.find("](").filter(|&end| end > 0)?;
+    let label = &after[..label_end];
+    let target = &after[label_end + 2..];
+    let url_end = target.find(')').filter(|&end| end > 0)?;
+    let url = &target[..url_end];
+    if url.contains(char::is_whitespace) {
+        return None;
+    }
+    let nested = Style {
+        link: Some(url.to_string()),
+        ..style.clone()
+    };
+    Some((
+        1 + label_end + 2 + url_end + 1,
+        Inner::Nested(label, nested),
+    ))
+}
+
+/// A bare `http://` or `https://` URL, minus trailing punctuation.
+fn bare_url<'a>(rest: &'a str, before: Option<char>, style: &Style) -> Option<(usize, Inner<'a>)> {
+    if style.link.is_some() || before.is_some_and(char::is_alphanumeric) {
+        return None;
+    }
+    let scheme = ["https://", "http://"]
+        .into_iter()
+        .find(|scheme| rest.starts_with(scheme))?;
+    let end = rest
+        .find(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '"' | '`'))
+        .unwrap_or(rest.len());
+    let mut url = &rest[..end];
+    loop {
+        let trimmed = url.trim_end_matches(['.', ',', ';', ':', '!', '?', '\'', '*']);
+        // Keep a closing parenthesis only if the URL opened one
+        let trimmed = match trimmed.strip_suffix(')') {
+            Some(inner) if inner.matches('(').count() <= inner.matches(')').count() => inner,
+            _ => trimmed,
+        };
+        if trimmed.len() == url.len() {
+            break;
+        }
+        url = trimmed;
+    }
+    if url.len() <= scheme.len() {
+        return None;
+    }
+    let span = Span {
+        text: url.to_string(),
+        style: Style {
+            link: Some(url.to_string()),
+            ..style.clone()
+        },
+    };
+    Some((url.len(), Inner::Text(span)))
+}
+
+/// `**bold**`, `__bold__`, `*emphasis*` or `_emphasis_`.
+///
+/// Markers must hug the text they wrap, and `_` only counts at word
+/// boundaries so `snake_case_names` stay as they are.
+fn emphasis<'a>(rest: &'a str, before: Option<char>, style: &Style) -> Option<(usize, Inner<'a>)> {
+    let marker = ["**", "__", "*", "_"]
+        .into_iter()
+        .find(|marker| rest.starts_with(marker))?;
+    let underscore = marker.starts_with('_');
+    if underscore && before.is_some_and(char::is_alphanumeric) {
+        return None;
+    }
+
+    let content = &rest[marker.len()..];
+    if content.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let mut from = 0;
+    let end = loop {
+        let end = from + content[from..].find(marker)?;
+        // A single marker must not be half of a double one
+        if marker.len() == 1 && content[end + 1..].starts_with(marker) {
+            from = end + 2;
+            continue;
+        }
+        let closes = end > 0
+            && !content[..end].ends_with(char::is_whitespace)
+            && !(underscore
+                && content[end + marker.len()..]
+                    .chars()
+                    .next()
+                    .is_some_and(char::is_alphanumeric));
+        if closes {
+            break end;
+        }
+        from = end + marker.len();
+    };
+
+    let nested = if marker.len() == 2 {
+        Style {
+            bold: true,
+            ..style.clone()
+        }
+    } else {
+        Style {
+            italic: true,
+            ..style.clone()
+        }
+    };
+    Some((
+        marker.len() * 2 + end,
+        Inner::Nested(&content[..end], nested),
+    ))
+}
@@ -1,36 +1,534 @@
 //! Note storage for lsnote.
 //!
-//! Notes are stored in `~/.lsnote/notes` with the format:
-//! `/full/path/to/file: note text`
+//! Notes are stored in the global `~/.lsnote/notes`, keyed by absolute path,
+//! and optionally in a project-local `.lsnote/notes` at the root of a project
+//! (found by walking up from the listed directory), keyed by path relative to
+//! that root so it can be committed and shared. When both have a note for the
+//! same file, `notes.precedence` in the config decides which one is shown.
+//!
+//! Each file starts with a version header followed by one record per line:
+//!
+//! ```text
+//! # lsnote notes v2
+//! /full/path/to/file<TAB>note text[<TAB>key=value...]
+//! ```
+//!
+//! The optional `key=value` attributes record the file's identity
+//! (`dev`, `ino`, `size`, `mtime` in nanoseconds and optionally a content
+//! `hash`) so a note can find its file again after the file is renamed or
+//! moved, when and by whom the note was
+//! written (`created`, `updated`, `author`), and its previous versions (one
+//! `prev=updated,author,text` per revision, oldest first).
+//!
+//! Two flags change what a note applies to. `glob=1` marks a pattern note:
+//! its path is a glob (`*`, `?`, `[...]`, `**`) and the note is shown on every
+//! matching file that has no note of its own. `inherit=1` marks a directory
+//! note that is also shown, dimmed, on everything inside the directory.
+//! `pending=1` marks a note set for a path that didn't exist yet; until the
+//! path appears, the note is listed as a ghost entry in its parent directory.
+//! `expires=` holds the Unix timestamp after which a temporary note is
+//! considered stale. `private=1` marks an encrypted note, whose text field
+//! holds the ciphertext (see the `private` module).
+//!
+//! Besides its text, a note has typed slots for an open to-do (`todo=`) and
+//! a warning (`warn=`), escaped like the text. The text may be empty when
+//! only a slot is set.
+//!
+//! All fields are escaped so that any path (including non-UTF-8 bytes) and
+//! any note text (including tabs and newlines) round-trip: `\\` is a literal
+//! backslash, `\t`, `\n` and `\r` are the usual control characters, and
+//! `\xHH` is an arbitrary byte.
+//!
+//! Files in the original unversioned `/full/path/to/file: note text` format
+//! are still read, and are rewritten in the current format on the next save.
+//!
+//! Saves write a temporary file and rename it into place, keeping the previous
+//! version as `notes.bak`. Read-modify-write cycles hold an advisory lock on
+//! `notes.lock` so concurrent invocations don't lose each other's changes.
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::{OnceCell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
 use std::fs;
-use std::io::{BufRead, BufReader, Write};
-use std::path::{Path, PathBuf};
+use std::io::Read;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+
+use glob::{MatchOptions, Pattern};
+use regex::Regex;
 
+use crate::checklist;
+use crate::config::{get_config, Backend};
 use crate::get_data_dir;
+use crate::git::{get_git_root, get_git_user};
+use crate::journal::{self, Store};
+use crate::private::NoteKey;
+#[cfg(feature = "sqlite")]
+use crate::sqlite::NotesDb;
+use crate::storage::{
+    entry_storage, git_notes_within, move_git_notes, storage_for, stores_per_file, NoteStorage,
+};
 
 const NOTES_FILE: &str = "notes";
+/// SQLite database holding the global notes with `notes.backend = sqlite`.
+const DB_FILE: &str = "notes.db";
+const PROJECT_DIR: &str = ".lsnote";
+const BACKUP_FILE: &str = "notes.bak";
+const LOCK_FILE: &str = "notes.lock";
+
+/// Header written as the first line of the notes file.
+pub const NOTES_HEADER: &str = "# lsnote notes v2";
+
+/// Files larger than this are not content-hashed.
+const MAX_HASH_SIZE: u64 = 64 * 1024 * 1024;
 
-/// Get the notes file path: ~/.lsnote/notes
-fn get_notes_path() -> Result<PathBuf, String> {
-    Ok(get_data_dir()?.join(NOTES_FILE))
+/// Number of previous versions kept per note.
+const MAX_HISTORY: usize = 20;
+
+/// A previous version of a note.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Revision {
+    pub text: String,
+    /// Unix timestamp of when this version was written.
+    pub updated: Option<i64>,
+    pub author: Option<String>,
 }
 
-/// Load all notes from the notes file.
-pub fn load_notes() -> HashMap<String, String> {
-    let mut map = HashMap::new();
+/// A note attached to a file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Note {
+    pub text: String,
+    /// Device and inode of the file when the note was set.
+    pub inode: Option<(u64, u64)>,
+    /// Content hash of the file when the note was set, if enabled.
+    pub hash: Option<u64>,
+    /// Size and modification time of the file when the note was set.
+    pub stamp: Option<(u64, i64)>,
+    /// `#tags` found in the text, without the `#`.
+    pub tags: Vec<String>,
+    /// Unix timestamps of when the note was first set and last changed.
+    pub created: Option<i64>,
+    pub updated: Option<i64>,
+    /// Who last changed the note.
+    pub author: Option<String>,
+    /// Previous versions, oldest first.
+    pub history: Vec<Revision>,
+    /// The note's path is a glob pattern matching the files it applies to.
+    pub glob: bool,
+    /// The note is also shown on everything inside its directory.
+    pub inherit: bool,
+    /// The note was set before its path existed.
+    pub pending: bool,
+    /// Unix timestamp after which the note is stale and due for cleanup.
+    pub expires: Option<i64>,
+    /// The text is encrypted; see [`NoteKey`].
+    pub private: bool,
+    /// Open to-do for the file.
+    pub todo: Option<String>,
+    /// Warning about the file.
+    pub warn: Option<String>,
+}
 
-    let notes_path = match get_notes_path() {
-        Ok(p) => p,
-        Err(_) => return map,
-    };
+/// The typed slots of a note.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum Kind {
+    /// The note's text.
+    #[default]
+    Note,
+    /// An open to-do.
+    Todo,
+    /// A warning.
+    Warn,
+}
+
+impl Kind {
+    /// Every slot, in the order they are shown.
+    pub const ALL: [Kind; 3] = [Kind::Note, Kind::Todo, Kind::Warn];
+
+    /// The slot's name for messages.
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Note => "note text",
+            Kind::Todo => "todo",
+            Kind::Warn => "warning",
+        }
+    }
+}
+
+impl Note {
+    /// Create a note from its text alone, extracting its tags.
+    pub fn from_text(text: String) -> Self {
+        let tags = extract_tags(&text);
+        Self {
+            text,
+            tags,
+            ..Self::default()
+        }
+    }
+
+    /// Check whether the note's expiry date has passed.
+    pub fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| expires <= chrono::Utc::now().timestamp())
+    }
+
+    /// The text in a slot, if the slot is set.
+    pub fn slot(&self, kind: Kind) -> Option<&str> {
+        match kind {
+            Kind::Note => Some(self.text.as_str()).filter(|t| !t.is_empty()),
+            Kind::Todo => self.todo.as_deref(),
+            Kind::Warn => self.warn.as_deref(),
+        }
+    }
+
+    /// Set or clear a slot. Tags are only taken from the note's text.
+    pub fn set_slot(&mut self, kind: Kind, text: Option<String>) {
+        match kind {
+            Kind::Note => {
+                self.text = text.unwrap_or_default();
+                self.tags = extract_tags(&self.text);
+            }
+            Kind::Todo => self.todo = text,
+            Kind::Warn => self.warn = text,
+        }
+    }
+
+    /// Check whether every slot is empty.
+    pub fn is_empty(&self) -> bool {
+        Kind::ALL.into_iter().all(|kind| self.slot(kind).is_none())
+    }
+
+    /// Check whether the note carries `tag` (compared case-insensitively).
+    pub fn has_tag(&self, tag: &str) -> bool {
+        let tag = tag.trim_start_matches('#');
+        self.tags.iter().any(|t| t.eq_ignore_ascii_case(tag))
+    }
+
+    /// Replace this note's text with `next`'s, keeping the current text in
+    /// the history and the original creation time.
+    ///
+    /// Private notes keep no history, and their text is left out of the
+    /// history of what replaces them.
+    pub fn revise(self, mut next: Note) -> Note {
+        next.created = self.created.or(next.created);
+        if next.private || self.private {
+            return next;
+        }
+        next.history = self.history;
+        // A note with only a slot set has no text worth keeping
+        if self.text != next.text && !self.text.is_empty() {
+            next.history.push(Revision {
+                text: self.text,
+                updated: self.updated,
+                author: self.author,
+            });
+            let excess = next.history.len().saturating_sub(MAX_HISTORY);
+            next.history.drain(..excess);
+        }
+        next
+    }
+
+    /// Create a note for the file at `path`, recording its identity, the
+    /// current time and the author.
+    pub fn new(path: &Path, text: &str) -> Self {
+        Self::with_author(path, text, current_author(note_dir(path)))
+    }
+
+    /// Like [`Note::new`], with an already determined author.
+    pub fn with_author(path: &Path, text: &str, author: Option<String>) -> Self {
+        let metadata = fs::metadata(path).ok();
+        let inode = metadata.as_ref().map(|m| (m.dev(), m.ino()));
+        let stamp = metadata.as_ref().map(file_stamp);
+        let hash = if get_config().notes.content_hash {
+            metadata
+                .filter(|m| m.is_file() && m.len() <= MAX_HASH_SIZE)
+                .and_then(|_| content_hash(path))
+        } else {
+            None
+        };
+
+        let now = chrono::Utc::now().timestamp();
+
+        Self {
+            inode,
+            hash,
+            stamp,
+            created: Some(now),
+            updated: Some(now),
+            author,
+            ..Self::from_text(text.to_string())
+        }
+    }
+}
+
+/// The author to record for notes written in `dir`: the git user name if
+/// configured, otherwise `$USER`.
+pub fn current_author(dir: &Path) -> Option<String> {
+    get_git_user(dir).or_else(|| std::env::var("USER").ok())
+}
+
+/// Byte ranges of the `#tags` in a note's text, including the `#`.
+///
+/// A tag is a `#` at the start of the text or after whitespace, followed by
+/// letters, digits, `_`, `-` or `/`.
+pub fn tag_spans(text: &str) -> Vec<Range<usize>> {
+    static TAG: OnceLock<Regex> = OnceLock::new();
+    let tag = TAG.get_or_init(|| Regex::new(r"(?:^|\s)(#[\w][\w/-]*)").unwrap());
+    tag.captures_iter(text)
+        .filter_map(|c| c.get(1))
+        .map(|m| m.range())
+        .collect()
+}
+
+/// Extract the distinct tags from a note's text, without the `#`.
+pub fn extract_tags(text: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for span in tag_spans(text) {
+        let tag = &text[span.start + 1..span.end];
+        if !tags.iter().any(|t| t.eq_ignore_ascii_case(tag)) {
+            tags.push(tag.to_string());
+        }
+    }
+    tags
+}
+
+/// Hash a file's contents with 64-bit FNV-1a.
+fn content_hash(path: &Path) -> Option<u64> {
+    let mut file = fs::File::open(path).ok()?;
+    let mut buf = [0u8; 8192];
+    let mut hash = FNV_OFFSET;
+    loop {
+        let n = file.read(&mut buf).ok()?;
+        if n == 0 {
+            return Some(hash);
+        }
+        hash = fnv1a(hash, &buf[..n]);
+    }
+}
+
+/// Hash bytes with 64-bit FNV-1a, like [`content_hash`] does a file's.
+pub fn bytes_hash(bytes: &[u8]) -> u64 {
+    fnv1a(FNV_OFFSET, bytes)
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Escape raw bytes into a single-line, tab-free string.
+pub fn escape(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                '\t' => out.push_str("\\t"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                c if c.is_control() && c.is_ascii() => out.push_str(&format!("\\x{:02x}", c as u8)),
+                c => out.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    out
+}
 
-    if let Ok(file) = fs::File::open(notes_path) {
-        let reader = BufReader::new(file);
-        for line in reader.lines().map_while(Result::ok) {
-            if let Some((path, note)) = line.split_once(": ") {
-                map.insert(path.to_string(), note.to_string());
+/// Reverse [`escape`]. Returns `None` for malformed escape sequences.
+pub fn unescape(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        match bytes.get(i + 1)? {
+            b'\\' => out.push(b'\\'),
+            b't' => out.push(b'\t'),
+            b'n' => out.push(b'\n'),
+            b'r' => out.push(b'\r'),
+            b'x' => {
+                let hex = s.get(i + 2..i + 4)?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 2;
+            }
+            _ => return None,
+        }
+        i += 2;
+    }
+    Some(out)
+}
+
+/// Parse a record line of the current format.
+fn parse_record(line: &str) -> Option<(PathBuf, Note)> {
+    let (path, fields) = line.split_once('\t')?;
+    let path = PathBuf::from(OsString::from_vec(unescape(path)?));
+    Some((path, parse_note_fields(fields)?))
+}
+
+/// Parse the fields of a record after the path: the note text followed by
+/// its `key=value` attributes.
+pub fn parse_note_fields(fields: &str) -> Option<Note> {
+    let mut fields = fields.split('\t');
+    let mut note = Note::from_text(unescape_string(fields.next()?)?);
+
+    let mut dev = None;
+    let mut ino = None;
+    let mut size = None;
+    let mut mtime = None;
+    for field in fields {
+        let Some((key, value)) = field.split_once('=') else {
+            continue;
+        };
+        match key {
+            "dev" => dev = value.parse().ok(),
+            "ino" => ino = value.parse().ok(),
+            "hash" => note.hash = u64::from_str_radix(value, 16).ok(),
+            "size" => size = value.parse().ok(),
+            "mtime" => mtime = value.parse().ok(),
+            "created" => note.created = value.parse().ok(),
+            "updated" => note.updated = value.parse().ok(),
+            "author" => note.author = unescape_string(value),
+            "prev" => note.history.extend(parse_revision(value)),
+            "glob" => note.glob = value == "1",
+            "inherit" => note.inherit = value == "1",
+            "pending" => note.pending = value == "1",
+            "expires" => note.expires = value.parse().ok(),
+            "private" => note.private = value == "1",
+            "todo" => note.todo = unescape_string(value),
+            "warn" => note.warn = unescape_string(value),
+            _ => {}
+        }
+    }
+    note.inode = dev.zip(ino);
+    note.stamp = size.zip(mtime);
+
+    Some(note)
+}
+
+/// Format a note as a record line of the current format.
+pub fn format_record(path: &Path, note: &Note) -> String {
+    format!(
+        "{}\t{}",
+        escape(path.as_os_str().as_bytes()),
+        format_note_fields(note)
+    )
+}
+
+/// Format the fields of a record after the path.
+pub fn format_note_fields(note: &Note) -> String {
+    let mut line = escape(note.text.as_bytes());
+    if let Some((dev, ino)) = note.inode {
+        line.push_str(&format!("\tdev={}\tino={}", dev, ino));
+    }
+    if let Some(hash) = note.hash {
+        line.push_str(&format!("\thash={:016x}", hash));
+    }
+    if let Some((size, mtime)) = note.stamp {
+        line.push_str(&format!("\tsize={}\tmtime={}", size, mtime));
+    }
+    if let Some(created) = note.created {
+        line.push_str(&format!("\tcreated={}", created));
+    }
+    if let Some(updated) = note.updated {
+        line.push_str(&format!("\tupdated={}", updated));
+    }
+    if let Some(author) = &note.author {
+        line.push_str(&format!("\tauthor={}", escape(author.as_bytes())));
+    }
+    for revision in &note.history {
+        line.push_str(&format!("\tprev={}", format_revision(revision)));
+    }
+    if note.glob {
+        line.push_str("\tglob=1");
+    }
+    if note.inherit {
+        line.push_str("\tinherit=1");
+    }
+    if note.pending {
+        line.push_str("\tpending=1");
+    }
+    if let Some(expires) = note.expires {
+        line.push_str(&format!("\texpires={}", expires));
+    }
+    if note.private {
+        line.push_str("\tprivate=1");
+    }
+    if let Some(todo) = &note.todo {
+        line.push_str(&format!("\ttodo={}", escape(todo.as_bytes())));
+    }
+    if let Some(warn) = &note.warn {
+        line.push_str(&format!("\twarn={}", escape(warn.as_bytes())));
+    }
+    line
+}
+
+/// Unescape a field that must be valid UTF-8.
+fn unescape_string(s: &str) -> Option<String> {
+    String::from_utf8(unescape(s)?).ok()
+}
+
+/// Parse a `prev` attribute value: `updated,author,text`.
+fn parse_revision(value: &str) -> Option<Revision> {
+    let mut parts = value.splitn(3, ',');
+    let updated = parts.next()?;
+    let author = parts.next()?;
+    Some(Revision {
+        updated: updated.parse().ok(),
+        author: Some(unescape_string(author)?).filter(|a| !a.is_empty()),
+        text: unescape_string(parts.next()?)?,
+    })
+}
+
+/// Format a `prev` attribute value. Commas in the author are escaped so the
+/// value splits unambiguously; the text comes last and may contain them.
+fn format_revision(revision: &Revision) -> String {
+    format!(
+        "{},{},{}",
+        revision.updated.map(|t| t.to_string()).unwrap_or_default(),
+        escape(revision.author.as_deref().unwrap_or("").as_bytes()).replace(',', "\\x2c"),
+        escape(revision.text.as_bytes())
+    )
+}
+
+/// Parse the contents of a notes file in either the current or legacy format.
+///
+/// Relative paths are resolved against `root`, escaped for glob patterns.
+pub fn parse_notes(content: &str, root: &Path) -> HashMap<PathBuf, Note> {
+    let mut map = HashMap::new();
+    let mut lines = content.lines().peekable();
+
+    if lines.peek() == Some(&NOTES_HEADER) {
+        lines.next();
+        let pattern_root = escape_pattern(root);
+        for line in lines {
+            if let Some((path, note)) = parse_record(line) {
+                let root = if note.glob { &pattern_root } else { root };
+                map.insert(root.join(path), note);
+            }
+        }
+    } else {
+        // Legacy format: `path: note`, split on the first ": "
+        for line in lines {
+            if let Some((path, text)) = line.split_once(": ") {
+                map.insert(root.join(path), Note::from_text(text.to_string()));
             }
         }
     }
@@ -38,51 +536,1473 @@ pub fn load_notes() -> HashMap<String, String> {
     map
 }
 
-/// Save all notes to the notes file.
-pub fn save_notes(notes: &HashMap<String, String>) -> Result<(), String> {
-    let notes_path = get_notes_path()?;
-    let mut file = fs::File::create(&notes_path).map_err(|e| e.to_string())?;
+/// Which notes file a note is stored in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// `~/.lsnote/notes`, private to the user.
+    Global,
+    /// `.lsnote/notes` at the project root, shared with the project.
+    Project,
+}
+
+/// A notes file on disk.
+pub struct NotesFile {
+    path: PathBuf,
+    /// Project root that paths are stored relative to. Global notes store
+    /// absolute paths, which resolve the same against any root; `/` is used.
+    root: PathBuf,
+    scope: Scope,
+    /// Whether this is the SQLite database rather than a text file.
+    sqlite: bool,
+}
+
+impl NotesFile {
+    /// The global notes file used by the configured backend.
+    pub fn global() -> Result<Self, String> {
+        Self::global_with(get_config().notes.backend)
+    }
+
+    /// The global notes file used by `backend`: `~/.lsnote/notes.db` for
+    /// SQLite, otherwise `~/.lsnote/notes`.
+    pub fn global_with(backend: Backend) -> Result<Self, String> {
+        let sqlite = backend == Backend::Sqlite;
+        if sqlite && !cfg!(feature = "sqlite") {
+            return Err("lsnote was built without SQLite support".to_string());
+        }
+        Ok(Self {
+            path: get_data_dir()?.join(if sqlite { DB_FILE } else { NOTES_FILE }),
+            root: PathBuf::from("/"),
+            scope: Scope::Global,
+            sqlite,
+        })
+    }
+
+    /// The project notes file for the project rooted at `root`.
+    pub fn project(root: &Path) -> Self {
+        Self {
+            path: root.join(PROJECT_DIR).join(NOTES_FILE),
+            root: root.to_path_buf(),
+            scope: Scope::Project,
+            sqlite: false,
+        }
+    }
+
+    /// The notes file of the given scope for notes on `path`.
+    ///
+    /// For the project scope this is the nearest enclosing project, falling
+    /// back to the git repository root when no project notes exist yet.
+    pub fn for_scope(scope: Scope, path: &Path) -> Result<Self, String> {
+        match scope {
+            Scope::Global => Self::global(),
+            Scope::Project => {
+                let dir = note_dir(path);
+                find_project_root(dir)
+                    .or_else(|| get_git_root(dir))
+                    .map(|root| Self::project(&root))
+                    .ok_or_else(|| {
+                        "Not inside a project (no .lsnote directory or git repository found)"
+                            .to_string()
+                    })
+            }
+        }
+    }
+
+    /// Whether notes for `canonical` can be kept in this file. Project files
+    /// only hold notes for paths inside the project.
+    pub fn contains(&self, canonical: &Path) -> bool {
+        self.scope == Scope::Global || canonical.starts_with(&self.root)
+    }
 
-    let mut entries: Vec<_> = notes.iter().collect();
-    entries.sort_by(|a, b| a.0.cmp(b.0));
+    /// Load all notes from this file, keyed by absolute path.
+    pub fn load(&self) -> HashMap<PathBuf, Note> {
+        if self.sqlite {
+            return self.load_db(None);
+        }
+        match fs::read(&self.path) {
+            Ok(bytes) => parse_notes(&String::from_utf8_lossy(&bytes), &self.root),
+            Err(_) => HashMap::new(),
+        }
+    }
 
-    for (path, note) in entries {
-        writeln!(file, "{}: {}", path, note).map_err(|e| e.to_string())?;
+    /// Load the notes for `path` and everything inside it. Only the database
+    /// can do this without reading every note.
+    pub fn load_within(&self, path: &Path) -> HashMap<PathBuf, Note> {
+        if self.sqlite {
+            self.load_db(Some(path))
+        } else {
+            self.load()
+        }
     }
 
+    /// Open the SQLite database backing this file.
+    #[cfg(feature = "sqlite")]
+    pub fn open_db(&self) -> Result<NotesDb, String> {
+        NotesDb::open(&self.path)
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn load_db(&self, within: Option<&Path>) -> HashMap<PathBuf, Note> {
+        self.open_db()
+            .and_then(|db| match within {
+                Some(path) => db.load_within(path),
+                None => db.load_all(),
+            })
+            .unwrap_or_default()
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn load_db(&self, _within: Option<&Path>) -> HashMap<PathBuf, Note> {
+        HashMap::new()
+    }
+
+    /// Save all notes to this file, recording the notes that change in the
+    /// undo journal first.
+    pub fn save(&self, notes: &HashMap<PathBuf, Note>) -> Result<(), String> {
+        journal::record_diff(&self.journal_store(), &self.load(), notes)?;
+        self.persist(notes)
+    }
+
+    /// Replace the backup with the current notes file, so it no longer
+    /// holds what was there before the last save.
+    pub fn refresh_backup(&self) -> Result<(), String> {
+        if self.sqlite {
+            return Ok(());
+        }
+        let _lock = self.lock()?;
+        backup_notes(&self.path)
+    }
+
+    /// Put notes back to previous states, applied in order, without
+    /// journaling them. `None` removes the note.
+    pub fn restore(&self, states: &[(PathBuf, Option<Note>)]) -> Result<(), String> {
+        let _lock = self.lock()?;
+        let mut notes = self.load();
+        for (path, note) in states {
+            match note {
+                Some(note) => notes.insert(path.clone(), note.clone()),
+                None => notes.remove(path),
+            };
+        }
+        self.persist(&notes)
+    }
+
+    /// How the undo journal refers to this file.
+    fn journal_store(&self) -> Store {
+        match self.scope {
+            Scope::Global if self.sqlite => Store::Database,
+            Scope::Global => Store::Global,
+            Scope::Project => Store::Project(self.root.clone()),
+        }
+    }
+
+    /// Write all notes to this file.
+    ///
+    /// The new contents are written to a temporary file and renamed over the
+    /// notes file, so a crash mid-write never leaves it truncated. The
+    /// database is updated in a single transaction instead.
+    fn persist(&self, notes: &HashMap<PathBuf, Note>) -> Result<(), String> {
+        if self.sqlite {
+            return self.save_db(notes);
+        }
+        if self.scope == Scope::Project {
+            self.create_project_dir()?;
+        }
+
+        let tmp_path =
+            self.path
+                .with_file_name(format!("{}.tmp.{}", NOTES_FILE, std::process::id()));
+
+        let result = self.write(&tmp_path, notes).and_then(|_| {
+            backup_notes(&self.path)?;
+            fs::rename(&tmp_path, &self.path).map_err(|e| e.to_string())
+        });
+
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn save_db(&self, notes: &HashMap<PathBuf, Note>) -> Result<(), String> {
+        self.open_db()?.replace_all(notes)
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn save_db(&self, _notes: &HashMap<PathBuf, Note>) -> Result<(), String> {
+        Err("lsnote was built without SQLite support".to_string())
+    }
+
+    /// Write notes in the current format to `path` and flush them to disk.
+    fn write(&self, path: &Path, notes: &HashMap<PathBuf, Note>) -> Result<(), String> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| e.to_string())?;
+        let mut writer = BufWriter::new(file);
+
+        let mut entries: Vec<_> = notes.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+
+        writeln!(writer, "{}", NOTES_HEADER).map_err(|e| e.to_string())?;
+        let pattern_root = escape_pattern(&self.root);
+        for (path, note) in entries {
+            let root = if note.glob { &pattern_root } else { &self.root };
+            let key = match (self.scope, path.strip_prefix(root)) {
+                (Scope::Global, _) | (_, Err(_)) => path.as_path(),
+                (Scope::Project, Ok(rel)) if rel.as_os_str().is_empty() => Path::new("."),
+                (Scope::Project, Ok(rel)) => rel,
+            };
+            writeln!(writer, "{}", format_record(key, note)).map_err(|e| e.to_string())?;
+        }
+
+        let file = writer.into_inner().map_err(|e| e.to_string())?;
+        file.sync_all().map_err(|e| e.to_string())
+    }
+
+    /// Create the project's `.lsnote` directory, ignoring lsnote's scratch
+    /// files so only the notes themselves get committed.
+    fn create_project_dir(&self) -> Result<(), String> {
+        let dir = self.root.join(PROJECT_DIR);
+        if dir.is_dir() {
+            return Ok(());
+        }
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        fs::write(
+            dir.join(".gitignore"),
+            format!("{}\n{}\n{}.tmp.*\n", BACKUP_FILE, LOCK_FILE, NOTES_FILE),
+        )
+        .map_err(|e| e.to_string())
+    }
+
+    /// Take the lock guarding this file's read-modify-write cycles.
+    pub fn lock(&self) -> Result<NotesLock, String> {
+        if self.scope == Scope::Project {
+            self.create_project_dir()?;
+        }
+        NotesLock::acquire(&self.path.with_file_name(LOCK_FILE))
+    }
+
+    /// Load, modify and save the notes while holding the lock.
+    ///
+    /// The notes are only saved if `f` succeeds and reports a change.
+    pub fn update<F>(&self, f: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut HashMap<PathBuf, Note>) -> Result<bool, String>,
+    {
+        let _lock = self.lock()?;
+        let mut notes = self.load();
+        if f(&mut notes)? {
+            self.save(&notes)?;
+        }
+        Ok(())
+    }
+}
+
+/// Find the root of the project containing `dir`: the nearest ancestor with
+/// a `.lsnote` directory, other than the global one in the home directory.
+pub fn find_project_root(dir: &Path) -> Option<PathBuf> {
+    let global = dirs::home_dir().map(|home| home.join(PROJECT_DIR));
+    dir.ancestors()
+        .find(|ancestor| {
+            let candidate = ancestor.join(PROJECT_DIR);
+            candidate.is_dir() && Some(&candidate) != global.as_ref()
+        })
+        .map(Path::to_path_buf)
+}
+
+/// All notes files that apply to `path`: the global file, then the enclosing
+/// project's file if there is one.
+///
+/// The order is fixed so that locks are always taken in the same order.
+fn notes_files(path: &Path) -> Vec<NotesFile> {
+    NotesFile::global()
+        .into_iter()
+        .chain(find_project_root(note_dir(path)).map(|root| NotesFile::project(&root)))
+        .collect()
+}
+
+/// The directory used to find the project and author of a note for `path`:
+/// the path itself if it is a directory, else its nearest existing ancestor.
+fn note_dir(path: &Path) -> &Path {
+    path.ancestors().find(|dir| dir.is_dir()).unwrap_or(path)
+}
+
+/// Keep the current notes file as `notes.bak` before it is replaced.
+fn backup_notes(notes_path: &Path) -> Result<(), String> {
+    if !notes_path.exists() {
+        return Ok(());
+    }
+    let backup_path = notes_path.with_file_name(BACKUP_FILE);
+    let _ = fs::remove_file(&backup_path);
+    if fs::hard_link(notes_path, &backup_path).is_err() {
+        fs::copy(notes_path, &backup_path)
+            .map_err(|e| format!("Could not back up notes: {}", e))?;
+    }
     Ok(())
 }
 
-/// Canonicalize a path to an absolute path string.
-fn canonicalize_path(path: &Path) -> Result<String, String> {
+/// Exclusive advisory lock on the notes file, released on drop.
+pub struct NotesLock {
+    _file: fs::File,
+}
+
+impl NotesLock {
+    /// Block until the lock at `lock_path` is acquired.
+    pub fn acquire(lock_path: &Path) -> Result<Self, String> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lock_path)
+            .map_err(|e| format!("Could not open lock file: {}", e))?;
+
+        // SAFETY: the descriptor is valid for as long as `file` is alive.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(format!(
+                "Could not lock notes: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        Ok(Self { _file: file })
+    }
+}
+
+/// Notes loaded once per invocation, for looking up many entries cheaply.
+///
+/// Listing code should hold one of these instead of calling
+/// [`get_note_details`] per entry, which re-reads the notes file every time.
+#[derive(Default)]
+pub struct NotesStore {
+    notes: HashMap<PathBuf, Note>,
+    by_inode: HashMap<(u64, u64), PathBuf>,
+    /// Pattern notes, most specific first.
+    patterns: Vec<(Pattern, PathBuf)>,
+    /// Backend holding notes outside the notes files, queried per entry.
+    storage: Option<Box<dyn NoteStorage>>,
+    /// Inherited note resolved for each directory looked at so far.
+    inherited: RefCell<HashMap<PathBuf, Option<Note>>>,
+    /// For each tag looked for so far, the paths with a note carrying it and
+    /// all their ancestors.
+    tagged: RefCell<HashMap<String, HashSet<PathBuf>>>,
+    /// Likewise for each slot kind.
+    kinded: RefCell<HashMap<Kind, HashSet<PathBuf>>>,
+    /// Total checklist progress of the notes inside each directory, built
+    /// the first time it's needed.
+    checklists: OnceCell<HashMap<PathBuf, (usize, usize)>>,
+}
+
+impl NotesStore {
+    /// Load the global notes and those of the project containing `path`,
+    /// enough to show `path` and everything inside it.
+    pub fn load_for(path: &Path) -> Self {
+        Self::load(path, true)
+    }
+
+    /// Load every global note and those of the project containing `path`.
+    pub fn load_all(path: &Path) -> Self {
+        Self::load(path, false)
+    }
+
+    fn load(path: &Path, within: bool) -> Self {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let mut files = notes_files(&path);
+
+        // Later files override earlier ones, so load the preferred one last
+        if get_config().notes.prefer_global {
+            files.reverse();
+        }
+
+        let mut notes = HashMap::new();
+        for file in &files {
+            if within {
+                notes.extend(file.load_within(&path));
+            } else {
+                notes.extend(file.load());
+            }
+        }
+        Self {
+            storage: entry_storage(),
+            ..Self::from_notes(notes)
+        }
+    }
+
+    fn from_notes(notes: HashMap<PathBuf, Note>) -> Self {
+        let by_inode = notes
+            .iter()
+            .filter_map(|(path, note)| Some((note.inode?, path.clone())))
+            .collect();
+        let mut patterns: Vec<_> = notes
+            .iter()
+            .filter(|(_, note)| note.glob)
+            .filter_map(|(path, _)| Some((Pattern::new(path.to_str()?).ok()?, path.clone())))
+            .collect();
+        patterns.sort_by_key(|(pattern, _)| pattern_specificity(pattern.as_str()));
+        Self {
+            notes,
+            by_inode,
+            patterns,
+            ..Self::default()
+        }
+    }
+
+    /// Get the note for an already canonicalized path.
+    ///
+    /// If no note is keyed by the path, falls back to an orphaned note (one
+    /// whose recorded path no longer exists) for the same device and inode,
+    /// so notes follow files that were renamed or moved.
+    ///
+    /// Notes kept by the storage backend (such as extended attributes) count
+    /// as global notes for precedence. A path with no note of its own gets
+    /// the note of the most specific pattern matching it, if any.
+    pub fn get_canonical(&self, canonical: &Path) -> Option<Cow<'_, Note>> {
+        let from_storage = || {
+            self.storage
+                .as_ref()
+                .and_then(|storage| storage.get(canonical))
+                .map(Cow::Owned)
+        };
+        let own = if get_config().notes.prefer_global {
+            from_storage().or_else(|| self.get_from_files(canonical).map(Cow::Borrowed))
+        } else {
+            self.get_from_files(canonical)
+                .map(Cow::Borrowed)
+                .or_else(from_storage)
+        };
+        own.or_else(|| self.match_pattern(canonical).map(Cow::Borrowed))
+    }
+
+    /// Get the note a path inherits from the nearest enclosing directory
+    /// whose note is set to be inherited.
+    pub fn get_inherited(&self, canonical: &Path) -> Option<Note> {
+        self.inherited_from(canonical.parent()?)
+    }
+
+    fn inherited_from(&self, dir: &Path) -> Option<Note> {
+        if let Some(note) = self.inherited.borrow().get(dir) {
+            return note.clone();
+        }
+        let note = match self.get_canonical(dir) {
+            Some(note) if note.inherit => Some(note.into_owned()),
+            _ => dir.parent().and_then(|parent| self.inherited_from(parent)),
+        };
+        self.inherited
+            .borrow_mut()
+            .insert(dir.to_path_buf(), note.clone());
+        note
+    }
+
+    /// Find the note of the most specific pattern matching a path.
+    fn match_pattern(&self, canonical: &Path) -> Option<&Note> {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        self.patterns
+            .iter()
+            .find(|(pattern, _)| pattern.matches_path_with(canonical, options))
+            .and_then(|(_, path)| self.notes.get(path))
+    }
+
+    /// Look up a note in the loaded notes files, following renamed files.
+    fn get_from_files(&self, canonical: &Path) -> Option<&Note> {
+        if let Some(note) = self.notes.get(canonical).filter(|n| !n.glob) {
+            return Some(note);
+        }
+        if self.by_inode.is_empty() {
+            return None;
+        }
+
+        let metadata = fs::metadata(canonical).ok()?;
+        let old_path = self.by_inode.get(&(metadata.dev(), metadata.ino()))?;
+        let note = self.notes.get(old_path)?;
+        is_orphan_of(old_path, note, canonical).then_some(note)
+    }
+
+    /// Check whether the note for `canonical`, or for anything inside it,
+    /// carries `tag`.
+    pub fn has_tag_within(&self, canonical: &Path, tag: &str) -> bool {
+        if self
+            .get_canonical(canonical)
+            .is_some_and(|n| n.has_tag(tag))
+        {
+            return true;
+        }
+        let key = tag.trim_start_matches('#').to_ascii_lowercase();
+        self.tagged
+            .borrow_mut()
+            .entry(key)
+            .or_insert_with(|| self.paths_and_ancestors(|note| note.has_tag(tag)))
+            .contains(canonical)
+    }
+
+    /// The paths whose note matches `f`, along with all their ancestors, so
+    /// that checking whether anything inside a directory matches is a lookup
+    /// rather than a scan of every note.
+    fn paths_and_ancestors(&self, f: impl Fn(&Note) -> bool) -> HashSet<PathBuf> {
+        let mut paths = HashSet::new();
+        for (path, _) in self.notes.iter().filter(|(_, note)| f(note)) {
+            for ancestor in path.ancestors() {
+                if !paths.insert(ancestor.to_path_buf()) {
+                    break;
+                }
+            }
+        }
+        paths
+    }
+
+    /// Check whether the note for `canonical`, or for anything inside it,
+    /// has the `kind` slot set.
+    pub fn has_kind_within(&self, canonical: &Path, kind: Kind) -> bool {
+        if self
+            .get_canonical(canonical)
+            .is_some_and(|n| n.slot(kind).is_some())
+        {
+            return true;
+        }
+        self.kinded
+            .borrow_mut()
+            .entry(kind)
+            .or_insert_with(|| self.paths_and_ancestors(|note| note.slot(kind).is_some()))
+            .contains(canonical)
+    }
+
+    /// Total checklist progress of the notes for `canonical` and everything
+    /// inside it, as items done and items in all, or `None` if none of them
+    /// has a checklist. Private notes are not counted.
+    pub fn checklist_within(&self, canonical: &Path) -> Option<(usize, usize)> {
+        let own = self
+            .get_canonical(canonical)
+            .filter(|n| !n.private)
+            .and_then(|n| checklist::progress(&n.text));
+        let inside = self
+            .checklists
+            .get_or_init(|| self.checklists_by_dir())
+            .get(canonical)
+            .copied();
+        own.into_iter()
+            .chain(inside)
+            .reduce(|(done, total), (d, t)| (done + d, total + t))
+    }
+
+    /// Add up the checklist progress of every note into each directory above
+    /// it, so each directory's total is a single lookup.
+    fn checklists_by_dir(&self) -> HashMap<PathBuf, (usize, usize)> {
+        let mut totals: HashMap<PathBuf, (usize, usize)> = HashMap::new();
+        for (path, note) in &self.notes {
+            if note.glob || note.private {
+                continue;
+            }
+            let Some((done, total)) = checklist::progress(&note.text) else {
+                continue;
+            };
+            for dir in path.ancestors().skip(1) {
+                let entry = totals.entry(dir.to_path_buf()).or_default();
+                entry.0 += done;
+                entry.1 += total;
+            }
+        }
+        totals
+    }
+
+    /// Count how many notes carry each tag, most used first.
+    pub fn tag_counts(&self) -> Vec<(String, usize)> {
+        let mut counts: HashMap<String, (String, usize)> = HashMap::new();
+        for tag in self.notes.values().flat_map(|n| &n.tags) {
+            let entry = counts
+                .entry(tag.to_lowercase())
+                .or_insert_with(|| (tag.clone(), 0));
+            entry.1 += 1;
+        }
+
+        let mut counts: Vec<_> = counts.into_values().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts
+    }
+
+    /// Find notes whose text or slots match `pattern`, sorted by path.
+    /// Private notes are never matched.
+    /// If `dir` is given, only notes for paths inside it are returned.
+    pub fn search(&self, pattern: &Regex, dir: Option<&Path>) -> Vec<(&Path, &Note)> {
+        let mut matches: Vec<_> = self
+            .notes
+            .iter()
+            .filter(|(path, _)| dir.is_none_or(|d| path.starts_with(d)))
+            .filter(|(_, note)| {
+                !note.private
+                    && Kind::ALL
+                        .into_iter()
+                        .filter_map(|kind| note.slot(kind))
+                        .any(|text| pattern.is_match(text))
+            })
+            .map(|(path, note)| (path.as_path(), note))
+            .collect();
+        matches.sort_by(|a, b| a.0.cmp(b.0));
+        matches
+    }
+
+    /// Pending notes for paths directly inside `dir` that don't exist yet,
+    /// sorted by path.
+    pub fn pending_in(&self, dir: &Path) -> Vec<(&Path, &Note)> {
+        let mut pending: Vec<_> = self
+            .notes
+            .iter()
+            .filter(|(path, note)| note.pending && !note.glob && path.parent() == Some(dir))
+            .filter(|(path, _)| fs::symlink_metadata(path).is_err())
+            .map(|(path, note)| (path.as_path(), note))
+            .collect();
+        pending.sort_by(|a, b| a.0.cmp(b.0));
+        pending
+    }
+
+    /// Notes that expire by `until` (a Unix timestamp), soonest first.
+    pub fn due(&self, until: i64) -> Vec<(&Path, &Note)> {
+        let mut due: Vec<_> = self
+            .notes
+            .iter()
+            .filter(|(_, note)| note.expires.is_some_and(|expires| expires <= until))
+            .map(|(path, note)| (path.as_path(), note))
+            .collect();
+        due.sort_by(|a, b| a.1.expires.cmp(&b.1.expires).then_with(|| a.0.cmp(b.0)));
+        due
+    }
+}
+
+/// Sort key ordering glob patterns from most to least specific: fewer `**`,
+/// then fewer wildcards, then longer.
+fn pattern_specificity(pattern: &str) -> (usize, usize, std::cmp::Reverse<usize>) {
+    let wildcards = pattern.matches(['*', '?', '[']).count();
+    (
+        pattern.matches("**").count(),
+        wildcards,
+        std::cmp::Reverse(pattern.len()),
+    )
+}
+
+/// The size and modification time (in nanoseconds) of a file, recorded with
+/// its note to tell it apart from a later file that reuses its inode.
+pub fn file_stamp(metadata: &fs::Metadata) -> (u64, i64) {
+    let mtime = metadata
+        .mtime()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(metadata.mtime_nsec());
+    (metadata.size(), mtime)
+}
+
+/// Check whether `note`, recorded for `old_path`, is an orphaned note that
+/// belongs to the file now at `canonical`.
+///
+/// The caller is expected to have matched the note by device and inode; this
+/// confirms the old path is gone. Since a deleted file's inode can be reused
+/// by an unrelated new file, something else has to match too: the content
+/// hash, the size and modification time, or the file having been created
+/// before the note was last set.
+pub fn is_orphan_of(old_path: &Path, note: &Note, canonical: &Path) -> bool {
+    if fs::symlink_metadata(old_path).is_ok() {
+        return false;
+    }
+    let Ok(metadata) = fs::metadata(canonical) else {
+        return false;
+    };
+    if note.hash.is_some() && content_hash(canonical) == note.hash {
+        return true;
+    }
+    if note.stamp == Some(file_stamp(&metadata)) {
+        return true;
+    }
+    let born = metadata
+        .created()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok());
+    match (born, note.updated) {
+        (Some(born), Some(updated)) => (born.as_secs() as i64) < updated,
+        _ => false,
+    }
+}
+
+/// Find the orphaned note, if any, that belongs to the file at `canonical`.
+fn find_orphan(notes: &HashMap<PathBuf, Note>, canonical: &Path) -> Option<PathBuf> {
+    let metadata = fs::metadata(canonical).ok()?;
+    let inode = (metadata.dev(), metadata.ino());
+    notes
+        .iter()
+        .find(|(old_path, note)| {
+            note.inode == Some(inode) && is_orphan_of(old_path, note, canonical)
+        })
+        .map(|(old_path, _)| old_path.clone())
+}
+
+/// Canonicalize a path to an absolute path.
+fn canonicalize_path(path: &Path) -> Result<PathBuf, String> {
     path.canonicalize()
         .map_err(|e| format!("Could not resolve path: {}", e))
-        .map(|p| p.to_string_lossy().to_string())
 }
 
-/// Set a note for a file.
-pub fn set_note(path: &Path, note: &str) -> Result<(), String> {
+/// Resolve a path that may not exist yet: `.` and `..` are resolved
+/// lexically, then the deepest existing ancestor is canonicalized and the
+/// rest of the path appended to it.
+fn resolve_missing_path(path: &Path) -> Result<PathBuf, String> {
+    let cwd = std::env::current_dir().map_err(|e| e.to_string())?;
+    let absolute = normalize_path(&cwd.join(path));
+    let existing = absolute
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(Path::new("/"));
+    let canonical = canonicalize_path(existing)?;
+    match absolute.strip_prefix(existing) {
+        Ok(rest) if !rest.as_os_str().is_empty() => Ok(canonical.join(rest)),
+        _ => Ok(canonical),
+    }
+}
+
+/// Canonicalize a path to look up its note, falling back to resolving it
+/// lexically so notes set for paths that don't exist yet can be found.
+fn note_path(path: &Path) -> Result<PathBuf, String> {
+    canonicalize_path(path).or_else(|e| resolve_missing_path(path).map_err(|_| e))
+}
+
+/// Settings for [`set_note`] beyond the note's text.
+#[derive(Debug, Default)]
+pub struct SetOptions {
+    /// Which slot of the note the text goes in.
+    pub kind: Kind,
+    /// Whether a directory's note is also shown on everything inside it;
+    /// `None` keeps the current setting.
+    pub inherit: Option<bool>,
+    /// Allow a path that doesn't exist yet, marking the note pending.
+    pub allow_missing: bool,
+    /// Unix timestamp the note expires at; `Some(None)` clears the expiry
+    /// and `None` keeps the current one.
+    pub expires: Option<Option<i64>>,
+    /// Whether the note is encrypted; `None` keeps the current setting.
+    pub private: Option<bool>,
+}
+
+/// Set a note for a file in the storage of the given scope.
+pub fn set_note(path: &Path, note: &str, scope: Scope, options: SetOptions) -> Result<(), String> {
+    let canonical = match canonicalize_path(path) {
+        Ok(canonical) => canonical,
+        Err(_) if options.allow_missing => resolve_missing_path(path)?,
+        Err(e) => return Err(e),
+    };
+    if options.inherit == Some(true) && !canonical.is_dir() {
+        return Err("Only directory notes can be inherited".to_string());
+    }
+
+    let storage = storage_for(scope)?;
+    unlock_ahead(storage.as_ref(), &canonical)?;
+    if options.private == Some(true) {
+        NoteKey::unlock()?;
+    }
+    let pending = fs::symlink_metadata(&canonical).is_err();
+
+    // The key of a public note made private, to seal its earlier versions with
+    let mut sealed = None;
+    storage.modify(
+        &canonical,
+        Box::new(|mut current| {
+            let key = unlock_current(&mut current)?;
+            let mut note = next_note(&canonical, current.as_ref(), options.kind, note);
+            if let Some(inherit) = options.inherit {
+                note.inherit = inherit;
+            }
+            if let Some(expires) = options.expires {
+                note.expires = expires;
+            }
+            note.pending = pending;
+            if !options.private.unwrap_or(key.is_some()) {
+                return Ok(Some(note));
+            }
+
+            let was_public = key.is_none() && current.is_some();
+            let key = match key {
+                Some(key) => key,
+                None => NoteKey::unlock()?,
+            };
+            key.seal(&mut note)?;
+            if was_public {
+                sealed = Some(key);
+            }
+            Ok(Some(note))
+        }),
+    )?;
+    if let Some(key) = sealed {
+        // Don't leave the plain text behind in the journal or the backup
+        journal::seal_path(&canonical, key)?;
+        NotesFile::global()?.refresh_backup()?;
+    }
+    Ok(())
+}
+
+/// Add text to the end of a slot of a file's note, or with `prepend` to its
+/// start, joined to the current text by `separator`. Creates the note if the
+/// file has none in the given scope.
+pub fn extend_note(
+    path: &Path,
+    text: &str,
+    scope: Scope,
+    kind: Kind,
+    prepend: bool,
+    separator: &str,
+) -> Result<(), String> {
     let canonical = canonicalize_path(path)?;
-    let mut notes = load_notes();
-    notes.insert(canonical, note.to_string());
-    save_notes(&notes)
+    let storage = storage_for(scope)?;
+    unlock_ahead(storage.as_ref(), &canonical)?;
+    storage.modify(
+        &canonical,
+        Box::new(|mut current| {
+            let key = unlock_current(&mut current)?;
+            let combined = match current.as_ref().and_then(|n| n.slot(kind)) {
+                None => text.to_string(),
+                Some(current) if prepend => format!("{}{}{}", text, separator, current),
+                Some(current) => format!("{}{}{}", current, separator, text),
+            };
+            let mut note = next_note(&canonical, current.as_ref(), kind, &combined);
+            if let Some(key) = key {
+                key.seal(&mut note)?;
+            }
+            Ok(Some(note))
+        }),
+    )
+}
+
+/// Rewrite the text of a file's note with `f`, which gets the current text
+/// (empty if the file has no note), keeping everything else about the note.
+pub fn update_text<F>(path: &Path, scope: Scope, f: F) -> Result<(), String>
+where
+    F: FnOnce(&str) -> Result<String, String>,
+{
+    let canonical = note_path(path)?;
+    let storage = storage_for(scope)?;
+    unlock_ahead(storage.as_ref(), &canonical)?;
+    let pending = fs::symlink_metadata(&canonical).is_err();
+    storage.modify(
+        &canonical,
+        Box::new(|mut current| {
+            if current.is_none() {
+                // Only notes that already exist may be for missing paths
+                canonicalize_path(path)?;
+            }
+            let key = unlock_current(&mut current)?;
+
+            let text = f(current.as_ref().map_or("", |n| n.text.as_str()))?;
+            let mut note = next_note(&canonical, current.as_ref(), Kind::Note, &text);
+            note.pending = pending;
+            if let Some(key) = key {
+                key.seal(&mut note)?;
+            }
+            Ok(Some(note))
+        }),
+    )
 }
 
-/// Get the note for a file, if one exists.
-pub fn get_note(path: &Path) -> Option<String> {
+/// Unlock the key ahead of changing the note for `canonical` if the note is
+/// private, so the passphrase isn't asked for while its storage is locked.
+fn unlock_ahead(storage: &dyn NoteStorage, canonical: &Path) -> Result<(), String> {
+    match storage.lookup(canonical) {
+        Some(note) if note.private => NoteKey::unlock().map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+/// Decrypt the current note in place if it is private, so what is kept of it
+/// can be carried over, returning the key to encrypt its replacement with.
+fn unlock_current(current: &mut Option<Note>) -> Result<Option<&'static NoteKey>, String> {
+    match current {
+        Some(note) if note.private => {
+            let key = NoteKey::unlock()?;
+            *note = key.reveal(note)?;
+            Ok(Some(key))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// A new note for `canonical` with `text` in the `kind` slot, keeping the
+/// other slots, inheritance and expiry of `current`.
+fn next_note(canonical: &Path, current: Option<&Note>, kind: Kind, text: &str) -> Note {
+    carry_over(Note::new(canonical, ""), current, kind, text)
+}
+
+/// Like [`next_note`], starting from an already created empty `note`.
+fn carry_over(mut note: Note, current: Option<&Note>, kind: Kind, text: &str) -> Note {
+    if let Some(current) = current {
+        for other in Kind::ALL {
+            note.set_slot(other, current.slot(other).map(str::to_string));
+        }
+        note.inherit = current.inherit;
+        note.expires = current.expires;
+    }
+    note.set_slot(kind, Some(text.to_string()));
+    note
+}
+
+/// Resolve a glob pattern relative to the current directory into the
+/// absolute pattern its note is stored under.
+///
+/// Only `pattern` is glob syntax; the current directory is escaped, so a
+/// directory named like `proj[1]` matches itself.
+fn pattern_path(pattern: &str) -> Result<PathBuf, String> {
+    if !pattern.contains(['*', '?', '[']) {
+        return Err(format!("'{}' is not a glob pattern", pattern));
+    }
+    Pattern::new(pattern).map_err(|e| format!("Invalid pattern '{}': {}", pattern, e))?;
+
+    let cwd = std::env::current_dir()
+        .and_then(|cwd| cwd.canonicalize())
+        .map_err(|e| e.to_string())?;
+    Ok(normalize_path(&escape_pattern(&cwd).join(pattern)))
+}
+
+/// Escape a directory for use as the literal start of a glob pattern.
+pub fn escape_pattern(dir: &Path) -> PathBuf {
+    PathBuf::from(Pattern::escape(&dir.to_string_lossy()))
+}
+
+/// Resolve `.` and `..` components without touching the filesystem.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// Set the note shown on every file matching a glob pattern (relative to
+/// the current directory) that has no note of its own.
+pub fn set_pattern_note(pattern: &str, note: &str, scope: Scope) -> Result<(), String> {
+    let path = pattern_path(pattern)?;
+    let cwd = std::env::current_dir().map_err(|e| e.to_string())?;
+    let file = NotesFile::for_scope(scope, &cwd)?;
+    if scope == Scope::Project && !path.starts_with(escape_pattern(&file.root)) {
+        return Err(format!("'{}' is outside the project", pattern));
+    }
+
+    let mut note = Note::with_author(&path, note, current_author(&cwd));
+    note.glob = true;
+    file.update(|notes| {
+        insert_note(notes, path, note, scope);
+        Ok(true)
+    })
+}
+
+/// Get the note of a glob pattern, if one was set.
+pub fn get_pattern_note(pattern: &str) -> Option<Note> {
+    let path = pattern_path(pattern).ok()?;
+    let cwd = std::env::current_dir().ok()?;
+    let mut files = notes_files(&cwd);
+    if !get_config().notes.prefer_global {
+        files.reverse();
+    }
+    files
+        .iter()
+        .find_map(|file| file.load().remove(&path).filter(|n| n.glob))
+}
+
+/// Remove the note of a glob pattern, from the given scope or from every
+/// notes file that has one.
+pub fn remove_pattern_note(pattern: &str, scope: Option<Scope>) -> Result<(), String> {
+    let path = pattern_path(pattern)?;
+    let cwd = std::env::current_dir().map_err(|e| e.to_string())?;
+    let files = match scope {
+        Some(scope) => vec![NotesFile::for_scope(scope, &cwd)?],
+        None => notes_files(&cwd),
+    };
+
+    let mut found = false;
+    for file in &files {
+        file.update(|notes| {
+            let removed = notes.remove(&path).is_some();
+            found |= removed;
+            Ok(removed)
+        })?;
+    }
+
+    if found {
+        Ok(())
+    } else {
+        Err("No note found".to_string())
+    }
+}
+
+/// Insert a note, keeping the previous one (if any) in its history.
+pub fn insert_note(
+    notes: &mut HashMap<PathBuf, Note>,
+    canonical: PathBuf,
+    mut note: Note,
+    scope: Scope,
+) {
+    if scope == Scope::Project {
+        // Inode numbers and times mean nothing on a teammate's machine
+        note.inode = None;
+        note.stamp = None;
+    }
+    let note = match notes.remove(&canonical) {
+        Some(old) => old.revise(note),
+        None => note,
+    };
+    notes.insert(canonical, note);
+}
+
+/// Set many notes with a single load and save of the notes file.
+///
+/// Each record is a line (or record) number, a path and the note text.
+/// Records whose path cannot be resolved are skipped and reported as
+/// `(number, error)`. Returns how many notes were set and the errors.
+pub fn set_notes_batch(
+    records: Vec<(usize, PathBuf, String)>,
+    scope: Scope,
+) -> Result<(usize, Vec<(usize, String)>), String> {
+    let cwd = std::env::current_dir().map_err(|e| e.to_string())?;
+    let file = NotesFile::for_scope(scope, &cwd)?;
+    let author = current_author(&cwd);
+
+    let mut errors = Vec::new();
+    let mut resolved = Vec::new();
+    for (number, path, text) in records {
+        match canonicalize_path(&path) {
+            Ok(canonical) if scope == Scope::Project && !canonical.starts_with(&file.root) => {
+                errors.push((
+                    number,
+                    format!("'{}' is outside the project", path.display()),
+                ));
+            }
+            Ok(canonical) => resolved.push((number, canonical, text)),
+            Err(e) => errors.push((number, format!("'{}': {}", path.display(), e))),
+        }
+    }
+
+    // Keep what each note has besides its text, like `set_note`. Private
+    // notes would need the passphrase, so they're left alone.
+    let mut count = 0;
+    let mut next = |number, canonical: &Path, current: Option<&Note>, text: &str| {
+        if current.is_some_and(|n| n.private) {
+            errors.push((
+                number,
+                format!("'{}' has a private note", canonical.display()),
+            ));
+            return None;
+        }
+        count += 1;
+        let note = Note::with_author(canonical, "", author.clone());
+        Some(carry_over(note, current, Kind::Note, text))
+    };
+    if scope == Scope::Global && stores_per_file() {
+        // Each note is stored on its own
+        let storage = storage_for(scope)?;
+        for (number, canonical, text) in resolved {
+            let current = storage.lookup(&canonical);
+            if let Some(note) = next(number, &canonical, current.as_ref(), &text) {
+                storage.set(&canonical, note)?;
+            }
+        }
+    } else if !resolved.is_empty() {
+        file.update(|notes| {
+            for (number, canonical, text) in resolved {
+                if let Some(note) = next(number, &canonical, notes.get(&canonical), &text) {
+                    insert_note(notes, canonical, note, scope);
+                }
+            }
+            Ok(true)
+        })?;
+    }
+    errors.sort_by_key(|(number, _)| *number);
+    Ok((count, errors))
+}
+
+/// Find which notes file the note shown for a file comes from.
+pub fn note_scope(path: &Path) -> Option<Scope> {
     let canonical = canonicalize_path(path).ok()?;
-    let notes = load_notes();
-    notes.get(&canonical).cloned()
+    let mut files = notes_files(&canonical);
+    if !get_config().notes.prefer_global {
+        files.reverse();
+    }
+    files
+        .iter()
+        .find(|file| file.load().contains_key(&canonical))
+        .map(|file| file.scope)
 }
 
-/// Remove the note from a file.
-pub fn remove_note(path: &Path) -> Result<(), String> {
-    let canonical = canonicalize_path(path)?;
-    let mut notes = load_notes();
+/// Get the full note for a file, including its metadata and history.
+pub fn get_note_details(path: &Path) -> Option<Note> {
+    let canonical = note_path(path).ok()?;
+    let store = NotesStore::load_for(&canonical);
+    store.get_canonical(&canonical).map(Cow::into_owned)
+}
 
-    if notes.remove(&canonical).is_some() {
-        save_notes(&notes)
+/// Remove the note from a file, from the given scope or from every scope
+/// that has one.
+pub fn remove_note(path: &Path, scope: Option<Scope>) -> Result<(), String> {
+    let canonical = note_path(path)?;
+    let mut found = false;
+    for storage in &storages_for(&canonical, scope)? {
+        found |= storage.remove(&canonical)?;
+    }
+
+    if found {
+        Ok(())
     } else {
         Err("No note found".to_string())
     }
 }
+
+/// Clear one slot of a file's note, removing the note if nothing is left.
+pub fn remove_slot(path: &Path, kind: Kind, scope: Option<Scope>) -> Result<(), String> {
+    let canonical = note_path(path)?;
+    let mut found = false;
+    for storage in &storages_for(&canonical, scope)? {
+        let Some(mut note) = storage
+            .lookup(&canonical)
+            .filter(|n| n.slot(kind).is_some())
+        else {
+            continue;
+        };
+        found = true;
+        note.set_slot(kind, None);
+        if note.is_empty() {
+            storage.remove(&canonical)?;
+        } else {
+            note.updated = Some(chrono::Utc::now().timestamp());
+            storage.set(&canonical, note)?;
+        }
+    }
+
+    if found {
+        Ok(())
+    } else {
+        Err(format!("No {} found", kind.name()))
+    }
+}
+
+/// The storages to remove a note from: the given scope's, or else every
+/// notes file that applies to `canonical`.
+fn storages_for(
+    canonical: &Path,
+    scope: Option<Scope>,
+) -> Result<Vec<Box<dyn NoteStorage>>, String> {
+    match scope {
+        Some(scope) => Ok(vec![storage_for(scope)?]),
+        None => notes_files(canonical)
+            .iter()
+            .map(|file| storage_for(file.scope))
+            .collect(),
+    }
+}
+
+/// Move or rename a file, carrying its note (and, for a directory, the notes
+/// of everything inside it) to the new location.
+///
+/// Like `mv`, moving onto an existing directory moves the file into it.
+pub fn move_with_note(src: &Path, dst: &Path) -> Result<PathBuf, String> {
+    let src_canonical = canonicalize_path(src)?;
+    let dst = if dst.is_dir() {
+        let name = src_canonical
+            .file_name()
+            .ok_or("Cannot move the root directory")?;
+        dst.join(name)
+    } else {
+        dst.to_path_buf()
+    };
+
+    let files = notes_files(&src_canonical);
+    let locks = files
+        .iter()
+        .map(NotesFile::lock)
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut maps: Vec<_> = files.iter().map(NotesFile::load).collect();
+
+    // Pick up a note left behind by an earlier plain `mv`
+    for notes in &mut maps {
+        if !notes.contains_key(&src_canonical) {
+            if let Some(old) = find_orphan(notes, &src_canonical) {
+                if let Some(note) = notes.remove(&old) {
+                    notes.insert(src_canonical.clone(), note);
+                }
+            }
+        }
+    }
+
+    // Git notes are keyed by path, so they have to be moved too
+    let git_notes = git_notes_within(&src_canonical);
+
+    rename(src, &dst)?;
+    let dst_canonical = canonicalize_path(&dst)?;
+
+    for (file, notes) in files.iter().zip(&mut maps) {
+        let moved: Vec<PathBuf> = notes
+            .keys()
+            .filter(|p| p.starts_with(&src_canonical))
+            .cloned()
+            .collect();
+        for old in &moved {
+            if let Some(note) = notes.remove(old) {
+                let rest = old.strip_prefix(&src_canonical).unwrap_or(Path::new(""));
+                let new = if rest.as_os_str().is_empty() {
+                    dst_canonical.clone()
+                } else {
+                    dst_canonical.join(rest)
+                };
+                notes.insert(new, note);
+            }
+        }
+        if !moved.is_empty() {
+            file.save(notes)?;
+        }
+    }
+    // Storing git notes clears their paths from the notes files, which locks them
+    drop(locks);
+    move_git_notes(git_notes, &src_canonical, &dst_canonical)?;
+
+    Ok(dst)
+}
+
+/// Rename `src` to `dst`, copying a regular file across filesystems where a
+/// rename isn't possible.
+fn rename(src: &Path, dst: &Path) -> Result<(), String> {
+    match fs::rename(src, dst) {
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            if fs::symlink_metadata(src).map_or(true, |m| !m.is_file()) {
+                return Err(format!(
+                    "Cannot move '{}' to another filesystem: only regular files can be copied across filesystems",
+                    src.display()
+                ));
+            }
+            fs::copy(src, dst).map_err(|e| format!("Could not move file: {}", e))?;
+            fs::remove_file(src).map_err(|e| format!("Could not move file: {}", e))
+        }
+        result => result.map_err(|e| format!("Could not move file: {}", e)),
+    }
+}
+
+/// Copy the global notes into the store used by `to` (the text file or the
+/// SQLite database) from the other one, replacing notes for the same paths.
+/// Returns how many notes were copied and where to.
+pub fn migrate_notes(to: Backend) -> Result<(usize, PathBuf), String> {
+    let from = match to {
+        Backend::File => Backend::Sqlite,
+        Backend::Sqlite => Backend::File,
+        Backend::Xattr | Backend::Git => {
+            return Err("Notes can only be migrated to file or sqlite".to_string())
+        }
+    };
+    let source = NotesFile::global_with(from)?;
+    let target = NotesFile::global_with(to)?;
+    if !source.path.exists() {
+        return Err(format!("No notes found at '{}'", source.path.display()));
+    }
+
+    let notes = source.load();
+    let count = notes.len();
+    target.update(|existing| {
+        existing.extend(notes);
+        Ok(count > 0)
+    })?;
+    Ok((count, target.path))
+}
+
+/// How many directory levels below an orphaned note's old directory to look
+/// for the file it was renamed or moved to.
+const RENAMED_DEPTH: usize = 3;
+
+/// Notes whose recorded path no longer exists, found by [`find_orphans`].
+pub struct Orphans {
+    /// The orphaned notes, sorted by path.
+    pub notes: Vec<(PathBuf, Note)>,
+    /// The new path of each note whose file was renamed or moved.
+    renamed: HashMap<PathBuf, PathBuf>,
+}
+
+/// Find notes whose recorded path no longer exists in the notes files that
+/// apply to `dir`. Pending notes are kept, since their paths aren't expected
+/// to exist yet, and so are notes whose file was renamed or moved (see
+/// [`find_renamed`]), since listings still show them.
+pub fn find_orphans(dir: &Path) -> Orphans {
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    let mut missing: Vec<_> = notes_files(&dir)
+        .iter()
+        .flat_map(|file| file.load())
+        .filter(|(path, note)| is_missing(path, note))
+        .collect();
+    missing.sort_by(|a, b| a.0.cmp(&b.0));
+    missing.dedup_by(|a, b| a.0 == b.0);
+    let renamed = find_renamed(&missing);
+    missing.retain(|(path, _)| !renamed.contains_key(path));
+    Orphans {
+        notes: missing,
+        renamed,
+    }
+}
+
+/// Check whether a note is for a file that no longer exists.
+fn is_missing(path: &Path, note: &Note) -> bool {
+    !note.glob && !note.pending && fs::symlink_metadata(path).is_err()
+}
+
+/// Find the files that notes with a missing path were renamed or moved to,
+/// matched by device and inode like [`find_orphan`]. Only looks near each
+/// note's old path: from its closest directory that still exists down to
+/// [`RENAMED_DEPTH`] levels. Returns the new path for each old one.
+fn find_renamed(missing: &[(PathBuf, Note)]) -> HashMap<PathBuf, PathBuf> {
+    let mut wanted: HashMap<(u64, u64), (&Path, &Note)> = missing
+        .iter()
+        .filter_map(|(path, note)| Some((note.inode?, (path.as_path(), note))))
+        .collect();
+    let mut renamed = HashMap::new();
+
+    let mut starts: Vec<&Path> = wanted
+        .values()
+        .filter_map(|(old, _)| old.ancestors().skip(1).find(|dir| dir.is_dir()))
+        .collect();
+    starts.sort();
+    starts.dedup();
+
+    // How deep below each directory has already been searched
+    let mut searched: HashMap<PathBuf, usize> = HashMap::new();
+    for start in starts {
+        let mut dirs = vec![(start.to_path_buf(), RENAMED_DEPTH)];
+        while let Some((dir, depth)) = dirs.pop() {
+            if wanted.is_empty() {
+                return renamed;
+            }
+            if searched.get(&dir).is_some_and(|&done| done >= depth) {
+                continue;
+            }
+            searched.insert(dir.clone(), depth);
+            let subdirs = match_renamed(&dir, &mut wanted, &mut renamed);
+            if depth > 0 {
+                dirs.extend(subdirs.into_iter().map(|subdir| (subdir, depth - 1)));
+            }
+        }
+    }
+    renamed
+}
+
+/// Match the entries of `dir` against the inodes of the `wanted` notes,
+/// moving each match into `renamed`. Returns the subdirectories of `dir`.
+fn match_renamed(
+    dir: &Path,
+    wanted: &mut HashMap<(u64, u64), (&Path, &Note)>,
+    renamed: &mut HashMap<PathBuf, PathBuf>,
+) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut subdirs = Vec::new();
+    for entry in entries.flatten() {
+        // Doesn't follow symlinks, so the walk can't loop
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let path = entry.path();
+        let inode = (metadata.dev(), metadata.ino());
+        if let Some((old, note)) = wanted.get(&inode).copied() {
+            if is_orphan_of(old, note, &path) {
+                wanted.remove(&inode);
+                renamed.insert(old.to_path_buf(), path.clone());
+            }
+        }
+        if metadata.is_dir() {
+            subdirs.push(path);
+        }
+    }
+    subdirs
+}
+
+/// Remove the `orphans` from the notes files that apply to `dir`, skipping
+/// any whose path has reappeared since they were found. Notes whose file was
+/// renamed or moved are re-keyed to its new path. Returns how many notes were
+/// removed.
+pub fn prune_notes(dir: &Path, orphans: &Orphans) -> Result<usize, String> {
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    let mut removed = 0;
+    for file in notes_files(&dir) {
+        file.update(|notes| {
+            let before = notes.len();
+            let mut moved = false;
+            for (old, new) in &orphans.renamed {
+                if notes.contains_key(new) || fs::symlink_metadata(old).is_ok() {
+                    continue;
+                }
+                if let Some(note) = notes.remove(old) {
+                    notes.insert(new.clone(), note);
+                    moved = true;
+                }
+            }
+            for (path, _) in &orphans.notes {
+                if fs::symlink_metadata(path).is_err() {
+                    notes.remove(path);
+                }
+            }
+            removed += before - notes.len();
+            Ok(notes.len() != before || moved)
+        })?;
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_round_trips_special_bytes() {
+        let cases: &[&[u8]] = &[
+            b"plain text",
+            b"tab\there",
+            b"two\nlines\r\n",
+            b"back\\slash and \\n literally",
+            b"\x00\x07\x1b\x7f controls",
+            b"\xff\xfe not utf-8 \x80",
+            "caf\u{e9} \u{2603}".as_bytes(),
+            b"",
+        ];
+        for &bytes in cases {
+            let escaped = escape(bytes);
+            assert!(!escaped.contains(['\t', '\n', '\r']), "{:?}", escaped);
+            assert_eq!(unescape(&escaped).as_deref(), Some(bytes), "{:?}", escaped);
+        }
+    }
+
+    #[test]
+    fn unescape_rejects_malformed_sequences() {
+        assert_eq!(unescape("trailing\\"), None);
+        assert_eq!(unescape("\\q"), None);
+        assert_eq!(unescape("\\x4"), None);
+        assert_eq!(unescape("\\xzz"), None);
+    }
+
+    #[test]
+    fn parse_notes_reads_legacy_format() {
+        let notes = parse_notes(
+            "src/main.rs: entry point\nREADME.md: docs: keep short\nno separator\n",
+            Path::new("/project"),
+        );
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[Path::new("/project/src/main.rs")].text, "entry point");
+        assert_eq!(
+            notes[Path::new("/project/README.md")].text,
+            "docs: keep short"
+        );
+    }
+
+    #[test]
+    fn parse_notes_round_trips_records() {
+        let path = PathBuf::from(OsString::from_vec(b"dir/we\tird\n\xffname".to_vec()));
+        let note = Note::from_text("line one\n\tindented \\ line two".to_string());
+        let content = format!("{}\n{}\n", NOTES_HEADER, format_record(&path, &note));
+
+        let notes = parse_notes(&content, Path::new("/root"));
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[&Path::new("/root").join(&path)].text, note.text);
+    }
+}
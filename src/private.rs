@@ -0,0 +1,233 @@
+//! Encrypted private notes.
+//!
+//! A private note's text is encrypted with ChaCha20-Poly1305 and stored hex
+//! encoded (nonce followed by ciphertext) in place of the plain text, so it
+//! is safe in the notes file, the database, extended attributes and the undo
+//! journal alike (whose recorded commands leave out its text). Listings show
+//! `🔒` for it unless run with `--unlock`.
+//!
+//! The key is derived with Argon2id from a passphrase (`$LSNOTE_PASSPHRASE`
+//! or a prompt), or from the contents of `notes.key_file` if configured. The
+//! salt lives in `~/.lsnote/private`, created on first use, together with a
+//! check value that catches a wrong passphrase:
+//!
+//! ```text
+//! # lsnote private v1
+//! salt=<hex>
+//! check=<hex>
+//! ```
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use crate::config::get_config;
+use crate::get_data_dir;
+use crate::notes::{Kind, Note};
+
+const PRIVATE_FILE: &str = "private";
+const PRIVATE_HEADER: &str = "# lsnote private v1";
+
+/// Plain text of the check value.
+const CHECK_TEXT: &str = "lsnote";
+
+/// Length of the nonce at the start of each encrypted note.
+const NONCE_LEN: usize = 12;
+
+/// What a private note shows until it is unlocked.
+pub const LOCKED: &str = "🔒";
+
+/// Key for encrypting and decrypting private notes.
+pub struct NoteKey {
+    cipher: ChaCha20Poly1305,
+}
+
+/// Path of the file holding the salt and check value: `~/.lsnote/private`.
+pub fn params_path() -> Result<PathBuf, String> {
+    Ok(get_data_dir()?.join(PRIVATE_FILE))
+}
+
+impl NoteKey {
+    /// Derive the key for private notes, setting up `~/.lsnote/private` on
+    /// first use. The key is derived once per invocation, so the passphrase
+    /// is only asked for once however many notes are read and written.
+    pub fn unlock() -> Result<&'static Self, String> {
+        static KEY: OnceLock<NoteKey> = OnceLock::new();
+        if let Some(key) = KEY.get() {
+            return Ok(key);
+        }
+        let key = Self::derive_from_params()?;
+        Ok(KEY.get_or_init(|| key))
+    }
+
+    fn derive_from_params() -> Result<Self, String> {
+        let path = params_path()?;
+        let params = fs::read_to_string(&path)
+            .ok()
+            .map(|content| parse_params(&content).ok_or("Corrupt private notes key file"))
+            .transpose()?;
+
+        let secret = read_secret(params.is_none())?;
+        match params {
+            Some((salt, check)) => {
+                let key = Self::derive(&secret, &salt)?;
+                if key.decrypt(&check).ok().as_deref() != Some(CHECK_TEXT) {
+                    return Err("Wrong passphrase or key file".to_string());
+                }
+                Ok(key)
+            }
+            None => {
+                let mut salt = [0u8; 16];
+                OsRng.fill_bytes(&mut salt);
+                let key = Self::derive(&secret, &salt)?;
+                let content = format!(
+                    "{}\nsalt={}\ncheck={}\n",
+                    PRIVATE_HEADER,
+                    to_hex(&salt),
+                    key.encrypt(CHECK_TEXT)?
+                );
+                write_private(&path, content.as_bytes())?;
+                Ok(key)
+            }
+        }
+    }
+
+    fn derive(secret: &[u8], salt: &[u8]) -> Result<Self, String> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(secret, salt, &mut key)
+            .map_err(|e| format!("Could not derive key: {}", e))?;
+        Ok(Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+        })
+    }
+
+    /// Encrypt text, returning the hex encoded nonce and ciphertext.
+    pub fn encrypt(&self, text: &str) -> Result<String, String> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, text.as_bytes())
+            .map_err(|_| "Could not encrypt note".to_string())?;
+        Ok(to_hex(&[nonce.as_slice(), &ciphertext].concat()))
+    }
+
+    /// Decrypt text produced by [`NoteKey::encrypt`].
+    pub fn decrypt(&self, data: &str) -> Result<String, String> {
+        let bytes = from_hex(data)
+            .filter(|b| b.len() > NONCE_LEN)
+            .ok_or("Corrupt private note")?;
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let plain = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "Could not decrypt private note (was it written with another key?)")?;
+        String::from_utf8(plain).map_err(|_| "Corrupt private note".to_string())
+    }
+
+    /// Encrypt a note's text and slots in place and mark it private.
+    pub fn seal(&self, note: &mut Note) -> Result<(), String> {
+        for kind in Kind::ALL {
+            if let Some(text) = note.slot(kind) {
+                let sealed = self.encrypt(text)?;
+                note.set_slot(kind, Some(sealed));
+            }
+        }
+        note.tags.clear();
+        note.private = true;
+        Ok(())
+    }
+
+    /// A copy of a private note with its text and slots decrypted.
+    pub fn reveal(&self, note: &Note) -> Result<Note, String> {
+        let mut revealed = Note {
+            private: false,
+            ..note.clone()
+        };
+        for kind in Kind::ALL {
+            if let Some(text) = note.slot(kind) {
+                revealed.set_slot(kind, Some(self.decrypt(text)?));
+            }
+        }
+        Ok(revealed)
+    }
+}
+
+/// Read the secret the key is derived from: the key file's contents, the
+/// passphrase in `$LSNOTE_PASSPHRASE`, or one typed at a prompt (twice when
+/// `new`, to catch typos).
+fn read_secret(new: bool) -> Result<Vec<u8>, String> {
+    if let Some(key_file) = &get_config().notes.key_file {
+        return fs::read(key_file)
+            .map_err(|e| format!("Could not read key file '{}': {}", key_file.display(), e));
+    }
+    if let Ok(passphrase) = std::env::var("LSNOTE_PASSPHRASE") {
+        return Ok(passphrase.into_bytes());
+    }
+
+    let prompt = |text: &str| {
+        rpassword::prompt_password(text).map_err(|e| format!("Could not read passphrase: {}", e))
+    };
+    let passphrase = if new {
+        let passphrase = prompt("New passphrase for private notes: ")?;
+        if prompt("Repeat passphrase: ")? != passphrase {
+            return Err("Passphrases don't match".to_string());
+        }
+        passphrase
+    } else {
+        prompt("Passphrase for private notes: ")?
+    };
+    if passphrase.is_empty() {
+        return Err("Empty passphrase".to_string());
+    }
+    Ok(passphrase.into_bytes())
+}
+
+/// Parse the salt and check value from `~/.lsnote/private`.
+fn parse_params(content: &str) -> Option<(Vec<u8>, String)> {
+    let mut lines = content.lines();
+    if lines.next()? != PRIVATE_HEADER {
+        return None;
+    }
+    let (mut salt, mut check) = (None, None);
+    for line in lines {
+        match line.split_once('=') {
+            Some(("salt", value)) => salt = from_hex(value),
+            Some(("check", value)) => check = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    Some((salt?, check?))
+}
+
+/// Create `path` readable only by its owner and write `content` to it.
+fn write_private(path: &Path, content: &[u8]) -> Result<(), String> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(content))
+        .map_err(|e| format!("Could not write '{}': {}", path.display(), e))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,243 @@
+//! SQLite notes database, used for global notes with `notes.backend = sqlite`.
+//!
+//! Each note is a row keyed by its canonical path (stored as raw bytes, so
+//! any path works) holding the same fields as a notes file record. Device
+//! and inode numbers get their own indexed columns so renamed files can be
+//! matched to their notes without scanning the table.
+//!
+//! Because paths are compared bytewise, the notes for everything inside a
+//! directory `/a/b` form the contiguous key range `/a/b/` .. `/a/b0` (`0`
+//! being the byte after `/`), which lets listings load just the notes they
+//! need. Pattern notes apply outside their own key range, so they are
+//! flagged in a `glob` column and always loaded.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::notes::{format_note_fields, is_orphan_of, parse_note_fields, Note};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS notes (
+        path BLOB PRIMARY KEY,
+        fields TEXT NOT NULL,
+        dev INTEGER,
+        ino INTEGER,
+        glob INTEGER NOT NULL DEFAULT 0
+    ) WITHOUT ROWID;
+    CREATE INDEX IF NOT EXISTS notes_inode ON notes (dev, ino);
+    CREATE INDEX IF NOT EXISTS notes_glob ON notes (glob) WHERE glob;
+";
+
+/// An open notes database.
+pub struct NotesDb {
+    conn: Connection,
+}
+
+fn sql_error(e: rusqlite::Error) -> String {
+    format!("Notes database error: {}", e)
+}
+
+fn path_bytes(path: &Path) -> &[u8] {
+    path.as_os_str().as_bytes()
+}
+
+/// Turn a row's path and fields back into a note.
+fn read_row(path: Vec<u8>, fields: String) -> Option<(PathBuf, Note)> {
+    let note = parse_note_fields(&fields)?;
+    Some((PathBuf::from(OsStr::from_bytes(&path)), note))
+}
+
+impl NotesDb {
+    /// Open the database at `path`, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let is_new = !path.exists();
+        let conn = Connection::open(path).map_err(sql_error)?;
+        if is_new {
+            // Notes can be sensitive, so keep them private to their owner
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+                .map_err(|e| e.to_string())?;
+        }
+        // Wait for other lsnote processes instead of failing
+        conn.busy_timeout(Duration::from_secs(5))
+            .map_err(sql_error)?;
+        conn.execute_batch(SCHEMA).map_err(sql_error)?;
+        Ok(Self { conn })
+    }
+
+    /// Get the note for a canonical path.
+    pub fn get(&self, canonical: &Path) -> Result<Option<Note>, String> {
+        let fields: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT fields FROM notes WHERE path = ?1",
+                params![path_bytes(canonical)],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(sql_error)?;
+        Ok(fields.and_then(|f| parse_note_fields(&f)))
+    }
+
+    /// Find the orphaned note, if any, that belongs to the file at
+    /// `canonical` because it was recorded for the same device and inode.
+    pub fn find_orphan(&self, canonical: &Path) -> Result<Option<(PathBuf, Note)>, String> {
+        let Ok(metadata) = fs::metadata(canonical) else {
+            return Ok(None);
+        };
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT path, fields FROM notes WHERE dev = ?1 AND ino = ?2")
+            .map_err(sql_error)?;
+        let rows = stmt
+            .query_map(
+                params![metadata.dev() as i64, metadata.ino() as i64],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(sql_error)?;
+
+        for row in rows {
+            let (path, fields) = row.map_err(sql_error)?;
+            if let Some((old_path, note)) = read_row(path, fields) {
+                if is_orphan_of(&old_path, &note, canonical) {
+                    return Ok(Some((old_path, note)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Load the notes for `path` and everything inside it, and every pattern
+    /// note.
+    pub fn load_within(&self, path: &Path) -> Result<HashMap<PathBuf, Note>, String> {
+        let bytes = path_bytes(path);
+        if bytes == b"/" {
+            return self.load_all();
+        }
+        let mut start = bytes.to_vec();
+        start.push(b'/');
+        let mut end = bytes.to_vec();
+        end.push(b'/' + 1);
+
+        self.query(
+            "SELECT path, fields FROM notes
+             WHERE path = ?1 OR (path >= ?2 AND path < ?3) OR glob",
+            params![bytes, start, end],
+        )
+    }
+
+    /// Load every note.
+    pub fn load_all(&self) -> Result<HashMap<PathBuf, Note>, String> {
+        self.query("SELECT path, fields FROM notes", [])
+    }
+
+    fn query<P: rusqlite::Params>(
+        &self,
+        sql: &str,
+        params: P,
+    ) -> Result<HashMap<PathBuf, Note>, String> {
+        let mut stmt = self.conn.prepare(sql).map_err(sql_error)?;
+        let rows = stmt
+            .query_map(params, |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(sql_error)?;
+
+        let mut notes = HashMap::new();
+        for row in rows {
+            let (path, fields) = row.map_err(sql_error)?;
+            notes.extend(read_row(path, fields));
+        }
+        Ok(notes)
+    }
+
+    /// Set the note for a canonical path, keeping the previous one (if any)
+    /// in its history.
+    pub fn set(&self, canonical: &Path, note: Note) -> Result<(), String> {
+        let tx = self.conn.unchecked_transaction().map_err(sql_error)?;
+        let fields: Option<String> = tx
+            .query_row(
+                "SELECT fields FROM notes WHERE path = ?1",
+                params![path_bytes(canonical)],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(sql_error)?;
+        let note = match fields.and_then(|f| parse_note_fields(&f)) {
+            Some(old) => old.revise(note),
+            None => note,
+        };
+        put(&tx, canonical, &note)?;
+        tx.commit().map_err(sql_error)
+    }
+
+    /// Remove the note for a canonical path. Returns whether there was one.
+    pub fn remove(&self, canonical: &Path) -> Result<bool, String> {
+        self.conn
+            .execute(
+                "DELETE FROM notes WHERE path = ?1",
+                params![path_bytes(canonical)],
+            )
+            .map(|count| count > 0)
+            .map_err(sql_error)
+    }
+
+    /// Make the database hold exactly `notes`, writing only the rows that
+    /// changed.
+    pub fn replace_all(&self, notes: &HashMap<PathBuf, Note>) -> Result<(), String> {
+        let tx = self.conn.unchecked_transaction().map_err(sql_error)?;
+        let current = {
+            let mut stmt = tx
+                .prepare("SELECT path, fields FROM notes")
+                .map_err(sql_error)?;
+            let rows = stmt
+                .query_map([], |row| {
+                    Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, String>(1)?))
+                })
+                .map_err(sql_error)?;
+            rows.collect::<Result<HashMap<_, _>, _>>()
+                .map_err(sql_error)?
+        };
+
+        for path in current.keys() {
+            if !notes.contains_key(Path::new(OsStr::from_bytes(path))) {
+                tx.execute("DELETE FROM notes WHERE path = ?1", params![path])
+                    .map_err(sql_error)?;
+            }
+        }
+        for (path, note) in notes {
+            if current.get(path_bytes(path)) != Some(&format_note_fields(note)) {
+                put(&tx, path, note)?;
+            }
+        }
+        tx.commit().map_err(sql_error)
+    }
+}
+
+/// Insert or replace a note's row.
+fn put(conn: &Connection, path: &Path, note: &Note) -> Result<(), String> {
+    let (dev, ino) = match note.inode {
+        Some((dev, ino)) => (Some(dev as i64), Some(ino as i64)),
+        None => (None, None),
+    };
+    conn.execute(
+        "INSERT OR REPLACE INTO notes (path, fields, dev, ino, glob)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            path_bytes(path),
+            format_note_fields(note),
+            dev,
+            ino,
+            note.glob
+        ],
+    )
+    .map(|_| ())
+    .map_err(sql_error)
+}
@@ -0,0 +1,585 @@
+//! Pluggable storage backends for notes.
+//!
+//! Notes live in notes files (see [`crate::notes`]) unless `notes.backend`
+//! in the config selects another backend for notes set in the global scope:
+//!
+//! - `file` (default): the global `~/.lsnote/notes` file.
+//! - `xattr`: a `user.lsnote.note` extended attribute on the file itself, so
+//!   the note travels with the file when it is copied along with its
+//!   attributes (`cp --preserve=xattr`, `rsync -X`). Where the filesystem
+//!   doesn't support user extended attributes, the note falls back to the
+//!   global notes file.
+//! - `sqlite`: a SQLite database at `~/.lsnote/notes.db`, with indexed
+//!   lookups so large note collections stay fast (see [`crate::sqlite`]).
+//! - `git`: for files in a git repository, a git note on `refs/notes/lsnote`
+//!   keyed by the file's repo-relative path, so notes travel with a push or
+//!   fetch of that ref (see [`crate::git`]). Each note holds one notes file
+//!   record with the relative path. Other files fall back to the global
+//!   notes file.
+//!
+//! Project notes are always kept in the project's notes file so they can be
+//! committed and shared.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::config::{get_config, Backend};
+use crate::get_data_dir;
+use crate::git::{get_git_root, read_git_note, read_git_notes, remove_git_note, write_git_note};
+use crate::journal::{self, Store};
+use crate::notes::{
+    format_note_fields, format_record, insert_note, parse_note_fields, parse_notes, Note,
+    NotesFile, NotesLock, Scope, NOTES_HEADER,
+};
+#[cfg(feature = "sqlite")]
+use crate::sqlite::NotesDb;
+
+/// Extended attribute holding a file's note.
+const XATTR_NAME: &str = "user.lsnote.note";
+
+/// Lock file serializing changes to notes kept outside notes files.
+const STORAGE_LOCK: &str = "storage.lock";
+
+/// Makes the next state of a note from its current one, for
+/// [`NoteStorage::modify`]. `None` removes the note.
+pub type Modify<'a> = Box<dyn FnOnce(Option<Note>) -> Result<Option<Note>, String> + 'a>;
+
+/// Where notes are stored.
+pub trait NoteStorage {
+    /// Get the note stored for a canonical path.
+    fn get(&self, canonical: &Path) -> Option<Note>;
+
+    /// Get the note stored for a canonical path wherever this storage put
+    /// it. Unlike [`NoteStorage::get`], which listings call for every entry,
+    /// this may be slow.
+    fn lookup(&self, canonical: &Path) -> Option<Note> {
+        self.get(canonical)
+    }
+
+    /// Store a note for a canonical path, keeping any previous note in its
+    /// history.
+    fn set(&self, canonical: &Path, note: Note) -> Result<(), String>;
+
+    /// Remove the note for a canonical path. Returns whether there was one.
+    fn remove(&self, canonical: &Path) -> Result<bool, String>;
+
+    /// Replace the note for a canonical path with what `f` makes of the
+    /// current one (as [`NoteStorage::lookup`] finds it), holding the
+    /// storage's lock throughout so no other change to the note is lost.
+    fn modify(&self, canonical: &Path, f: Modify<'_>) -> Result<(), String>;
+}
+
+/// Lock notes kept in extended attributes or git notes against changes by
+/// other processes, until the returned lock is dropped.
+pub fn lock_storage() -> Result<NotesLock, String> {
+    NotesLock::acquire(&get_data_dir()?.join(STORAGE_LOCK))
+}
+
+/// The storage notes of the given scope are written to.
+pub fn storage_for(scope: Scope) -> Result<Box<dyn NoteStorage>, String> {
+    if scope == Scope::Project {
+        return Ok(Box::new(FileStorage::new(scope)));
+    }
+    match get_config().notes.backend {
+        Backend::File => Ok(Box::new(FileStorage::new(scope))),
+        Backend::Xattr => Ok(Box::new(XattrStorage::new())),
+        Backend::Git => Ok(Box::new(GitStorage::new())),
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => Ok(Box::new(SqliteStorage::open()?)),
+        #[cfg(not(feature = "sqlite"))]
+        Backend::Sqlite => Err("lsnote was built without SQLite support".to_string()),
+    }
+}
+
+/// Storage that listings must query per entry, if the configured backend
+/// keeps notes that loading the notes files can miss: those in extended
+/// attributes or git notes, or database notes for files renamed from outside
+/// the listed directory.
+pub fn entry_storage() -> Option<Box<dyn NoteStorage>> {
+    match get_config().notes.backend {
+        Backend::File => None,
+        Backend::Xattr => Some(Box::new(XattrStorage::new())),
+        Backend::Git => Some(Box::new(GitStorage::new())),
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => SqliteStorage::open()
+            .ok()
+            .map(|s| Box::new(s) as Box<dyn NoteStorage>),
+        #[cfg(not(feature = "sqlite"))]
+        Backend::Sqlite => None,
+    }
+}
+
+/// Whether the configured backend stores each note on its own (`xattr` or
+/// `git`) rather than in one notes file.
+pub fn stores_per_file() -> bool {
+    matches!(get_config().notes.backend, Backend::Xattr | Backend::Git)
+}
+
+/// Notes kept in the global or project notes file.
+pub struct FileStorage {
+    scope: Scope,
+}
+
+impl FileStorage {
+    pub fn new(scope: Scope) -> Self {
+        Self { scope }
+    }
+}
+
+impl NoteStorage for FileStorage {
+    fn get(&self, canonical: &Path) -> Option<Note> {
+        let file = NotesFile::for_scope(self.scope, canonical).ok()?;
+        file.load().remove(canonical)
+    }
+
+    fn set(&self, canonical: &Path, note: Note) -> Result<(), String> {
+        let file = NotesFile::for_scope(self.scope, canonical)?;
+        if !file.contains(canonical) {
+            return Err(format!("'{}' is outside the project", canonical.display()));
+        }
+        file.update(|notes| {
+            insert_note(notes, canonical.to_path_buf(), note, self.scope);
+            Ok(true)
+        })
+    }
+
+    fn remove(&self, canonical: &Path) -> Result<bool, String> {
+        let file = NotesFile::for_scope(self.scope, canonical)?;
+        let mut found = false;
+        file.update(|notes| {
+            found = notes.remove(canonical).is_some();
+            Ok(found)
+        })?;
+        Ok(found)
+    }
+
+    fn modify(&self, canonical: &Path, f: Modify<'_>) -> Result<(), String> {
+        let file = NotesFile::for_scope(self.scope, canonical)?;
+        if !file.contains(canonical) {
+            return Err(format!("'{}' is outside the project", canonical.display()));
+        }
+        file.update(|notes| match f(notes.get(canonical).cloned())? {
+            Some(note) => {
+                insert_note(notes, canonical.to_path_buf(), note, self.scope);
+                Ok(true)
+            }
+            None => Ok(notes.remove(canonical).is_some()),
+        })
+    }
+}
+
+/// Notes kept in an extended attribute on each file, falling back to the
+/// global notes file where extended attributes can't be written.
+///
+/// [`NoteStorage::get`] only reads the attribute; notes that fell back to
+/// the notes file are found through the notes file as usual.
+pub struct XattrStorage {
+    fallback: FileStorage,
+}
+
+impl XattrStorage {
+    pub fn new() -> Self {
+        Self {
+            fallback: FileStorage::new(Scope::Global),
+        }
+    }
+}
+
+impl NoteStorage for XattrStorage {
+    fn get(&self, canonical: &Path) -> Option<Note> {
+        let value = sys::get(canonical, XATTR_NAME).ok()??;
+        parse_note_fields(&String::from_utf8_lossy(&value))
+    }
+
+    fn lookup(&self, canonical: &Path) -> Option<Note> {
+        self.get(canonical)
+            .or_else(|| self.fallback.lookup(canonical))
+    }
+
+    fn set(&self, canonical: &Path, mut note: Note) -> Result<(), String> {
+        // The attribute is bound to the file, so it needs no identity
+        note.inode = None;
+        note.hash = None;
+        note.stamp = None;
+        let old = self.get(canonical);
+        let note = match old.clone() {
+            Some(old) => old.revise(note),
+            None => note,
+        };
+
+        match sys::set(canonical, XATTR_NAME, format_note_fields(&note).as_bytes()) {
+            Ok(()) => {
+                journal::record(&Store::Xattr, &[(canonical.to_path_buf(), old)])?;
+                // Drop any older copy in the notes file so it can't shadow this one
+                self.fallback.remove(canonical)?;
+                Ok(())
+            }
+            // Unsupported filesystem, read-only file, value too large, ...
+            Err(_) => self.fallback.set(canonical, note),
+        }
+    }
+
+    fn remove(&self, canonical: &Path) -> Result<bool, String> {
+        let old = self.get(canonical);
+        let removed_attr = sys::remove(canonical, XATTR_NAME).unwrap_or(false);
+        if removed_attr {
+            journal::record(&Store::Xattr, &[(canonical.to_path_buf(), old)])?;
+        }
+        let removed_file = self.fallback.remove(canonical)?;
+        Ok(removed_attr || removed_file)
+    }
+
+    fn modify(&self, canonical: &Path, f: Modify<'_>) -> Result<(), String> {
+        let _lock = lock_storage()?;
+        match f(self.lookup(canonical))? {
+            Some(note) => self.set(canonical, note),
+            None => self.remove(canonical).map(|_| ()),
+        }
+    }
+}
+
+/// Notes kept as git notes in each file's repository, falling back to the
+/// global notes file for files outside any repository.
+///
+/// [`NoteStorage::get`] reads every note of a repository the first time it
+/// is asked about a file in it, so listings run git once per repository
+/// rather than once per entry.
+pub struct GitStorage {
+    fallback: FileStorage,
+    /// Repository root found for each directory looked at so far.
+    roots: RefCell<HashMap<PathBuf, Option<PathBuf>>>,
+    /// Notes loaded for each repository so far.
+    notes: RefCell<HashMap<PathBuf, HashMap<PathBuf, Note>>>,
+}
+
+impl GitStorage {
+    pub fn new() -> Self {
+        Self {
+            fallback: FileStorage::new(Scope::Global),
+            roots: RefCell::new(HashMap::new()),
+            notes: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// The root of the repository `canonical` is in, and its path relative
+    /// to the root.
+    fn locate(&self, canonical: &Path) -> Option<(PathBuf, PathBuf)> {
+        let dir = if canonical.is_dir() {
+            canonical
+        } else {
+            canonical.parent()?
+        };
+        let dir = dir.ancestors().find(|d| d.is_dir())?;
+        let root = self
+            .roots
+            .borrow_mut()
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| get_git_root(dir))
+            .clone()?;
+        let rel = canonical.strip_prefix(&root).ok()?;
+        // The repository root's own note, if any, stays in the notes file
+        if rel.as_os_str().is_empty() {
+            return None;
+        }
+        Some((root.clone(), rel.to_path_buf()))
+    }
+}
+
+impl NoteStorage for GitStorage {
+    fn get(&self, canonical: &Path) -> Option<Note> {
+        let (root, _) = self.locate(canonical)?;
+        let mut notes = self.notes.borrow_mut();
+        let notes = notes
+            .entry(root.clone())
+            .or_insert_with(|| parse_git_notes(&read_git_notes(&root), &root));
+        notes.get(canonical).cloned()
+    }
+
+    fn lookup(&self, canonical: &Path) -> Option<Note> {
+        let from_git = self.locate(canonical).and_then(|(root, rel)| {
+            parse_git_notes(&[read_git_note(&root, &rel)?], &root).remove(canonical)
+        });
+        from_git.or_else(|| self.fallback.lookup(canonical))
+    }
+
+    fn set(&self, canonical: &Path, mut note: Note) -> Result<(), String> {
+        let Some((root, rel)) = self.locate(canonical) else {
+            return self.fallback.set(canonical, note);
+        };
+        // The note is keyed by path, and inodes and times differ between clones
+        note.inode = None;
+        note.stamp = None;
+        let old = read_git_note(&root, &rel)
+            .and_then(|content| parse_git_notes(&[content], &root).remove(canonical));
+        let note = match old.clone() {
+            Some(old) => old.revise(note),
+            None => note,
+        };
+
+        journal::record(&Store::Git(root.clone()), &[(canonical.to_path_buf(), old)])?;
+        write_git_note(&root, &rel, &format_record(&rel, &note))?;
+        // Drop any older copy in the notes file so it can't shadow this one
+        self.fallback.remove(canonical)?;
+        Ok(())
+    }
+
+    fn remove(&self, canonical: &Path) -> Result<bool, String> {
+        let removed_note = match self.locate(canonical) {
+            Some((root, rel)) => {
+                let old = read_git_note(&root, &rel)
+                    .and_then(|content| parse_git_notes(&[content], &root).remove(canonical));
+                if old.is_some() {
+                    journal::record(&Store::Git(root.clone()), &[(canonical.to_path_buf(), old)])?;
+                }
+                remove_git_note(&root, &rel)?
+            }
+            None => false,
+        };
+        let removed_file = self.fallback.remove(canonical)?;
+        Ok(removed_note || removed_file)
+    }
+
+    fn modify(&self, canonical: &Path, f: Modify<'_>) -> Result<(), String> {
+        let _lock = lock_storage()?;
+        match f(self.lookup(canonical))? {
+            Some(note) => self.set(canonical, note),
+            None => self.remove(canonical).map(|_| ()),
+        }
+    }
+}
+
+/// Git notes for `canonical` and everything inside it, if the configured
+/// backend is `git`.
+pub fn git_notes_within(canonical: &Path) -> Vec<(PathBuf, Note)> {
+    if get_config().notes.backend != Backend::Git {
+        return Vec::new();
+    }
+    let Some((root, _)) = GitStorage::new().locate(canonical) else {
+        return Vec::new();
+    };
+    parse_git_notes(&read_git_notes(&root), &root)
+        .into_iter()
+        .filter(|(path, _)| path.starts_with(canonical))
+        .collect()
+}
+
+/// Move git notes found by [`git_notes_within`] for `src` to the same paths
+/// under `dst`, after the files were moved.
+pub fn move_git_notes(notes: Vec<(PathBuf, Note)>, src: &Path, dst: &Path) -> Result<(), String> {
+    let storage = GitStorage::new();
+    for (old, note) in notes {
+        let rest = old.strip_prefix(src).unwrap_or(Path::new(""));
+        let new = if rest.as_os_str().is_empty() {
+            dst.to_path_buf()
+        } else {
+            dst.join(rest)
+        };
+        storage.remove(&old)?;
+        storage.set(&new, note)?;
+    }
+    Ok(())
+}
+
+/// Parse git notes, each a notes file record keyed by a path relative to
+/// the repository `root`.
+fn parse_git_notes(contents: &[String], root: &Path) -> HashMap<PathBuf, Note> {
+    let mut records = format!("{}\n", NOTES_HEADER);
+    for content in contents {
+        records.push_str(content.trim_end_matches('\n'));
+        records.push('\n');
+    }
+    parse_notes(&records, root)
+}
+
+/// Put notes in the git repository at `root` back to previous states,
+/// without journaling them. `None` removes the note.
+pub fn restore_git(root: &Path, states: &[(PathBuf, Option<Note>)]) -> Result<(), String> {
+    for (canonical, note) in states {
+        let rel = canonical
+            .strip_prefix(root)
+            .map_err(|_| format!("'{}' is outside '{}'", canonical.display(), root.display()))?;
+        match note {
+            Some(note) => write_git_note(root, rel, &format_record(rel, note))?,
+            None => {
+                remove_git_note(root, rel)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Notes kept in the global SQLite database, read and written one row at a
+/// time.
+#[cfg(feature = "sqlite")]
+pub struct SqliteStorage {
+    db: NotesDb,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStorage {
+    pub fn open() -> Result<Self, String> {
+        Ok(Self {
+            db: NotesFile::global_with(Backend::Sqlite)?.open_db()?,
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl NoteStorage for SqliteStorage {
+    fn get(&self, canonical: &Path) -> Option<Note> {
+        if let Some(note) = self.db.get(canonical).ok()? {
+            return Some(note);
+        }
+        self.db.find_orphan(canonical).ok()?.map(|(_, note)| note)
+    }
+
+    fn set(&self, canonical: &Path, note: Note) -> Result<(), String> {
+        let old = self.db.get(canonical)?;
+        journal::record(&Store::Database, &[(canonical.to_path_buf(), old)])?;
+        self.db.set(canonical, note)
+    }
+
+    fn remove(&self, canonical: &Path) -> Result<bool, String> {
+        let Some(old) = self.db.get(canonical)? else {
+            return Ok(false);
+        };
+        journal::record(&Store::Database, &[(canonical.to_path_buf(), Some(old))])?;
+        self.db.remove(canonical)
+    }
+
+    fn modify(&self, canonical: &Path, f: Modify<'_>) -> Result<(), String> {
+        // The same lock as loading and saving the whole database
+        let _lock = NotesFile::global_with(Backend::Sqlite)?.lock()?;
+        match f(self.get(canonical))? {
+            Some(note) => self.set(canonical, note),
+            None => self.remove(canonical).map(|_| ()),
+        }
+    }
+}
+
+/// Put a file's extended attribute note back to a previous state, without
+/// journaling it. `None` removes the note.
+pub fn restore_xattr(canonical: &Path, note: Option<&Note>) -> Result<(), String> {
+    let result = match note {
+        Some(note) => sys::set(canonical, XATTR_NAME, format_note_fields(note).as_bytes()),
+        None => sys::remove(canonical, XATTR_NAME).map(|_| ()),
+    };
+    result.map_err(|e| format!("'{}': {}", canonical.display(), e))
+}
+
+/// Thin wrappers over the extended attribute system calls.
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    fn c_string(bytes: &[u8]) -> io::Result<CString> {
+        CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// Read an attribute, or `None` if the file doesn't have it.
+    pub fn get(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let path = c_string(path.as_os_str().as_bytes())?;
+        let name = c_string(name.as_bytes())?;
+        loop {
+            // SAFETY: both strings are NUL-terminated; a null buffer of size 0
+            // asks for the value's size.
+            let size =
+                unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+            if size < 0 {
+                let err = io::Error::last_os_error();
+                return match err.raw_os_error() {
+                    Some(libc::ENODATA) => Ok(None),
+                    _ => Err(err),
+                };
+            }
+
+            let mut buf = vec![0u8; size as usize];
+            // SAFETY: `buf` is valid for writes of `buf.len()` bytes.
+            let read = unsafe {
+                libc::getxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    buf.as_mut_ptr().cast(),
+                    buf.len(),
+                )
+            };
+            if read >= 0 {
+                buf.truncate(read as usize);
+                return Ok(Some(buf));
+            }
+            // The value grew between the two calls; try again
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ERANGE) {
+                return Err(err);
+            }
+        }
+    }
+
+    /// Create or replace an attribute.
+    pub fn set(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        let path = c_string(path.as_os_str().as_bytes())?;
+        let name = c_string(name.as_bytes())?;
+        // SAFETY: both strings are NUL-terminated and `value` is valid for
+        // reads of `value.len()` bytes.
+        let result = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Remove an attribute. Returns whether the file had it.
+    pub fn remove(path: &Path, name: &str) -> io::Result<bool> {
+        let path = c_string(path.as_os_str().as_bytes())?;
+        let name = c_string(name.as_bytes())?;
+        // SAFETY: both strings are NUL-terminated.
+        if unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) } == 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::ENODATA) => Ok(false),
+            _ => Err(err),
+        }
+    }
+}
+
+/// Extended attributes are only supported on Linux; elsewhere every call
+/// fails so notes fall back to the notes file.
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io;
+    use std::path::Path;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "extended attributes not supported",
+        )
+    }
+
+    pub fn get(_path: &Path, _name: &str) -> io::Result<Option<Vec<u8>>> {
+        Err(unsupported())
+    }
+
+    pub fn set(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn remove(_path: &Path, _name: &str) -> io::Result<bool> {
+        Err(unsupported())
+    }
+}
@@ -0,0 +1,382 @@
+//! Syncing the global notes through a shared directory.
+//!
+//! `lsnote notes sync` merges the global notes with `lsnote.notes` in a
+//! directory kept in sync between machines by another tool (Syncthing, a
+//! network share, ...), then writes the merged notes to both. The shared file
+//! is a regular notes file, except that paths inside the home directory are
+//! stored as `~/...` so they map onto each machine's own home directory, and
+//! no inode numbers are kept since they mean nothing on another machine.
+//!
+//! When both sides changed a note, the one changed last wins and the other
+//! version is kept in its history; such conflicts are reported. To tell
+//! which side changed a note, and a deleted note from a new one,
+//! `~/.lsnote/sync` records the notes as of the last sync on this machine,
+//! each with a hash of its record (without the file identity), since
+//! `updated` alone can't tell apart two changes within the same second:
+//!
+//! ```text
+//! # lsnote sync v2
+//! /full/path/to/file<TAB>updated<TAB>hash
+//! ```
+//!
+//! Lines without a hash, written by earlier versions, fall back to
+//! comparing `updated`.
+//!
+//! Private notes stay encrypted. So that the same passphrase unlocks them on
+//! every machine, `~/.lsnote/private` (the key's salt and check value) is
+//! shared as `lsnote.private` too: whichever side lacks it gets a copy. If
+//! both sides have one and they differ, private notes can't be read on the
+//! other side, so they are left out of the sync and reported.
+
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::config::{get_config, Backend};
+use crate::get_data_dir;
+use crate::notes::{
+    bytes_hash, escape, escape_pattern, file_stamp, format_record, parse_notes, unescape, Note,
+    NotesFile, NotesLock, Revision, NOTES_HEADER,
+};
+use crate::private::params_path;
+
+const SHARED_FILE: &str = "lsnote.notes";
+const SHARED_KEY: &str = "lsnote.private";
+const STATE_FILE: &str = "sync";
+/// Lock file in the data directory, so the shared directory holds nothing
+/// but the notes and the key's salt.
+const SYNC_LOCK: &str = "sync.lock";
+const STATE_HEADER: &str = "# lsnote sync v2";
+
+/// A note as of the last sync.
+struct Synced {
+    updated: Option<i64>,
+    /// Hash of the note's record, see [`record_hash`].
+    hash: Option<u64>,
+}
+
+impl Synced {
+    /// Whether `note` is unchanged since the last sync.
+    fn matches(&self, note: &Note) -> bool {
+        match self.hash {
+            Some(hash) => record_hash(note) == hash,
+            None => self.updated == note.updated,
+        }
+    }
+}
+
+/// A note changed on both sides since the last sync.
+pub struct Conflict {
+    pub path: PathBuf,
+    /// Whether this machine's version won.
+    pub kept_local: bool,
+}
+
+/// What a sync changed.
+#[derive(Default)]
+pub struct SyncReport {
+    /// Notes taken from the shared directory.
+    pub pulled: usize,
+    /// Notes written to the shared directory.
+    pub pushed: usize,
+    /// Notes deleted on one side and so removed from the other.
+    pub deleted: usize,
+    pub conflicts: Vec<Conflict>,
+    /// Private notes left out because the two sides use different keys.
+    pub skipped_private: usize,
+}
+
+/// Sync the global notes with the notes file in `dir`. With `dry_run`,
+/// only report what would change.
+pub fn sync(dir: &Path, dry_run: bool) -> Result<SyncReport, String> {
+    match get_config().notes.backend {
+        Backend::Xattr => {
+            return Err("Notes kept in extended attributes can't be synced".to_string())
+        }
+        Backend::Git => {
+            return Err("Notes kept in git notes are synced with git push and fetch".to_string())
+        }
+        Backend::File | Backend::Sqlite => {}
+    }
+    if !dir.is_dir() {
+        return Err(format!("'{}' is not a directory", dir.display()));
+    }
+    let home = dirs::home_dir().ok_or("Could not determine home directory")?;
+    let shared_path = dir.join(SHARED_FILE);
+    let _lock = NotesLock::acquire(&get_data_dir()?.join(SYNC_LOCK))?;
+
+    let mut shared = read_shared(&shared_path, &home)?;
+    let base = read_state()?;
+    let mut report = SyncReport::default();
+    let same_key = sync_key(&dir.join(SHARED_KEY), dry_run)?;
+    NotesFile::global()?.update(|local| {
+        let changed = merge(local, &mut shared, &base, same_key, &mut report);
+        Ok(changed && !dry_run)
+    })?;
+
+    if !dry_run {
+        write_shared(&shared_path, &shared, &home)?;
+        write_state(&shared)?;
+    }
+    Ok(report)
+}
+
+/// Share the private notes key's salt and check value through `shared_key`,
+/// copying it to whichever side lacks it. Returns whether both sides use the
+/// same one, so private notes written on one can be read on the other.
+fn sync_key(shared_key: &Path, dry_run: bool) -> Result<bool, String> {
+    let local_key = params_path()?;
+    match (fs::read(&local_key).ok(), fs::read(shared_key).ok()) {
+        (Some(mine), Some(theirs)) => Ok(mine == theirs),
+        (Some(mine), None) if !dry_run => write_atomic(shared_key, &mine).map(|_| true),
+        (None, Some(theirs)) if !dry_run => write_atomic(&local_key, &theirs).map(|_| true),
+        _ => Ok(true),
+    }
+}
+
+/// Merge the local and shared notes into each other, using `base` (the
+/// notes as of the last sync) to tell which side changed a note.
+/// Unless `same_key`, private notes are left as they are on both sides.
+/// Returns whether the local notes changed.
+fn merge(
+    local: &mut HashMap<PathBuf, Note>,
+    shared: &mut HashMap<PathBuf, Note>,
+    base: &HashMap<PathBuf, Synced>,
+    same_key: bool,
+    report: &mut SyncReport,
+) -> bool {
+    let paths: BTreeSet<PathBuf> = local.keys().chain(shared.keys()).cloned().collect();
+    let mut local_changed = false;
+    for path in paths {
+        let unchanged = |note: &Note| base.get(&path).is_some_and(|synced| synced.matches(note));
+        let (mine, theirs) = (local.get(&path), shared.get(&path));
+        if !same_key && mine.into_iter().chain(theirs).any(|n| n.private) {
+            report.skipped_private += 1;
+            continue;
+        }
+        match (mine, theirs) {
+            (Some(mine), Some(theirs)) => {
+                if same_note(mine, theirs) {
+                    continue;
+                }
+                let mine_changed = !unchanged(mine);
+                let theirs_changed = !unchanged(theirs);
+                let conflict = mine_changed && theirs_changed;
+                let keep_local = if conflict {
+                    mine.updated >= theirs.updated
+                } else {
+                    mine_changed
+                };
+
+                let (mut winner, loser) = if keep_local {
+                    (mine.clone(), theirs)
+                } else {
+                    (localize(&path, theirs), mine)
+                };
+                if conflict {
+                    keep_in_history(&mut winner, loser);
+                    report.conflicts.push(Conflict {
+                        path: path.clone(),
+                        kept_local: keep_local,
+                    });
+                }
+                if keep_local {
+                    report.pushed += 1;
+                } else {
+                    report.pulled += 1;
+                }
+                if !keep_local || conflict {
+                    local.insert(path.clone(), winner.clone());
+                    local_changed = true;
+                }
+                shared.insert(path, winner);
+            }
+            (Some(mine), None) => {
+                if unchanged(mine) {
+                    // Deleted elsewhere since the last sync
+                    local.remove(&path);
+                    local_changed = true;
+                    report.deleted += 1;
+                } else {
+                    shared.insert(path, mine.clone());
+                    report.pushed += 1;
+                }
+            }
+            (None, Some(theirs)) => {
+                if unchanged(theirs) {
+                    // Deleted here since the last sync
+                    shared.remove(&path);
+                    report.deleted += 1;
+                } else {
+                    local.insert(path.clone(), localize(&path, theirs));
+                    local_changed = true;
+                    report.pulled += 1;
+                }
+            }
+            (None, None) => {}
+        }
+    }
+    local_changed
+}
+
+/// Check whether two notes are the same apart from the file identity, which
+/// the shared file doesn't keep.
+fn same_note(a: &Note, b: &Note) -> bool {
+    shareable(a) == shareable(b)
+}
+
+/// A note without the file identity, as the shared file keeps it.
+fn shareable(note: &Note) -> Note {
+    Note {
+        inode: None,
+        stamp: None,
+        ..note.clone()
+    }
+}
+
+/// Hash of a note's record without the file identity, the same on every
+/// machine.
+fn record_hash(note: &Note) -> u64 {
+    bytes_hash(format_record(Path::new(""), &shareable(note)).as_bytes())
+}
+
+/// A note from the shared file, with the identity of the local file at
+/// `path` so it can follow the file when renamed.
+fn localize(path: &Path, note: &Note) -> Note {
+    let metadata = fs::metadata(path).ok();
+    Note {
+        inode: metadata.as_ref().map(|m| (m.dev(), m.ino())),
+        stamp: metadata.as_ref().map(file_stamp),
+        ..note.clone()
+    }
+}
+
+/// Keep the text of the version that lost a conflict in the winner's
+/// history, so nothing is lost. Private notes keep no history.
+fn keep_in_history(winner: &mut Note, loser: &Note) {
+    if winner.private || loser.private || winner.text == loser.text {
+        return;
+    }
+    let revision = Revision {
+        text: loser.text.clone(),
+        updated: loser.updated,
+        author: loser.author.clone(),
+    };
+    let at = winner
+        .history
+        .iter()
+        .position(|r| r.updated > revision.updated)
+        .unwrap_or(winner.history.len());
+    winner.history.insert(at, revision);
+}
+
+/// Read the shared notes, mapping `~/...` paths into `home`.
+fn read_shared(path: &Path, home: &Path) -> Result<HashMap<PathBuf, Note>, String> {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(format!("Could not read '{}': {}", path.display(), e)),
+    };
+    let notes = parse_notes(&String::from_utf8_lossy(&content), Path::new(""));
+    Ok(notes
+        .into_iter()
+        .map(|(path, note)| {
+            let path = match path.strip_prefix("~") {
+                Ok(rest) if note.glob => escape_pattern(home).join(rest),
+                Ok(rest) => home.join(rest),
+                Err(_) => path,
+            };
+            (path, note)
+        })
+        .collect())
+}
+
+/// Write the shared notes, storing paths inside `home` as `~/...`.
+fn write_shared(path: &Path, notes: &HashMap<PathBuf, Note>, home: &Path) -> Result<(), String> {
+    let pattern_home = escape_pattern(home);
+    let mut entries: Vec<(PathBuf, &Note)> = notes
+        .iter()
+        .map(|(path, note)| {
+            let home = if note.glob { &pattern_home } else { home };
+            match path.strip_prefix(home) {
+                Ok(rest) => (Path::new("~").join(rest), note),
+                Err(_) => (path.clone(), note),
+            }
+        })
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut out = format!("{}\n", NOTES_HEADER);
+    for (path, note) in entries {
+        out.push_str(&format_record(&path, &shareable(note)));
+        out.push('\n');
+    }
+    write_atomic(path, out.as_bytes())
+}
+
+fn state_path() -> Result<PathBuf, String> {
+    Ok(get_data_dir()?.join(STATE_FILE))
+}
+
+/// Read each note as of the last sync.
+fn read_state() -> Result<HashMap<PathBuf, Synced>, String> {
+    let content = fs::read(state_path()?).unwrap_or_default();
+    let content = String::from_utf8_lossy(&content);
+    Ok(content
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let path = PathBuf::from(OsString::from_vec(unescape(fields.next()?)?));
+            let updated = fields.next()?.parse().ok();
+            let hash = fields
+                .next()
+                .and_then(|hash| u64::from_str_radix(hash, 16).ok());
+            Some((path, Synced { updated, hash }))
+        })
+        .collect())
+}
+
+/// Record the synced notes as the base for the next sync.
+fn write_state(notes: &HashMap<PathBuf, Note>) -> Result<(), String> {
+    let mut entries: Vec<_> = notes.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut out = format!("{}\n", STATE_HEADER);
+    for (path, note) in entries {
+        out.push_str(&format!(
+            "{}\t{}\t{:016x}\n",
+            escape(path.as_os_str().as_bytes()),
+            note.updated.map(|t| t.to_string()).unwrap_or_default(),
+            record_hash(note)
+        ));
+    }
+    write_atomic(&state_path()?, out.as_bytes())
+}
+
+/// Write `content` to a temporary file readable only by its owner and
+/// rename it over `path`.
+fn write_atomic(path: &Path, content: &[u8]) -> Result<(), String> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = path.with_file_name(format!("{}.tmp.{}", file_name, std::process::id()));
+    let result = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(content)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|e| format!("Could not write '{}': {}", path.display(), e));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
@@ -0,0 +1,355 @@
+//! Bulk import and export of notes.
+//!
+//! Notes can be exported from a notes file as JSON or CSV and imported back,
+//! on the same machine or another, in a single load and save of the notes.
+//!
+//! JSON exports look like:
+//!
+//! ```text
+//! {"version": 1, "notes": [{"path": "/abs/path", "note": "text",
+//!   "created": 1700000000, "updated": 1700000000, "author": "name",
+//!   "history": [{"text": "old", "updated": 1690000000, "author": "name"}]}]}
+//! ```
+//!
+//! Pattern notes, inherited directory notes and notes for paths that don't
+//! exist yet also carry `"glob": true`, `"inherit": true` or `"pending": true`,
+//! and notes with an expiry date carry it as an `"expires"` timestamp.
+//! A note's to-do and warning are exported as `"todo"` and `"warn"`.
+//! Private notes are exported still encrypted, marked `"private": true`.
+//!
+//! CSV exports have a `path,note,created,updated,author` header row and omit
+//! the history, flags and slots, and so private notes and notes without text
+//! too.
+
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::notes::{file_stamp, Note, NotesFile, Revision};
+
+const CSV_HEADER: [&str; 5] = ["path", "note", "created", "updated", "author"];
+
+/// Serialization format for import and export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    /// Guess the format from a file extension, defaulting to JSON.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => Format::Csv,
+            _ => Format::Json,
+        }
+    }
+}
+
+/// How imported notes are combined with existing ones for the same path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Strategy {
+    /// Keep whichever note was updated more recently.
+    Merge,
+    /// Imported notes replace existing ones.
+    Overwrite,
+    /// Existing notes are left alone.
+    SkipExisting,
+}
+
+/// Outcome of an import.
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub skipped: usize,
+}
+
+/// Export every note in `file`, sorted by path.
+///
+/// Paths that are not valid UTF-8 cannot be represented and are skipped;
+/// they are returned alongside the output so the caller can warn about them.
+pub fn export_notes(file: &NotesFile, format: Format) -> (String, Vec<PathBuf>) {
+    let mut entries: Vec<_> = file.load().into_iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let (entries, skipped): (Vec<_>, Vec<_>) = entries
+        .into_iter()
+        .partition(|(path, _)| path.to_str().is_some());
+    let skipped = skipped.into_iter().map(|(path, _)| path).collect();
+
+    let output = match format {
+        Format::Json => export_json(&entries),
+        Format::Csv => export_csv(&entries),
+    };
+    (output, skipped)
+}
+
+fn export_json(entries: &[(PathBuf, Note)]) -> String {
+    let notes: Vec<Value> = entries
+        .iter()
+        .map(|(path, note)| {
+            let history: Vec<Value> = note
+                .history
+                .iter()
+                .map(|r| json!({"text": r.text, "updated": r.updated, "author": r.author}))
+                .collect();
+            let mut entry = json!({
+                "path": path.to_string_lossy(),
+                "note": note.text,
+                "created": note.created,
+                "updated": note.updated,
+                "author": note.author,
+                "history": history,
+            });
+            if note.glob {
+                entry["glob"] = json!(true);
+            }
+            if note.inherit {
+                entry["inherit"] = json!(true);
+            }
+            if note.pending {
+                entry["pending"] = json!(true);
+            }
+            if let Some(expires) = note.expires {
+                entry["expires"] = json!(expires);
+            }
+            if note.private {
+                entry["private"] = json!(true);
+            }
+            if let Some(todo) = &note.todo {
+                entry["todo"] = json!(todo);
+            }
+            if let Some(warn) = &note.warn {
+                entry["warn"] = json!(warn);
+            }
+            entry
+        })
+        .collect();
+
+    let doc = json!({"version": 1, "notes": notes});
+    let mut output = serde_json::to_string_pretty(&doc).unwrap_or_default();
+    output.push('\n');
+    output
+}
+
+fn export_csv(entries: &[(PathBuf, Note)]) -> String {
+    let mut output = csv_row(&CSV_HEADER.map(String::from));
+    for (path, note) in entries
+        .iter()
+        .filter(|(_, note)| !note.private && !note.text.is_empty())
+    {
+        output.push_str(&csv_row(&[
+            path.to_string_lossy().to_string(),
+            note.text.clone(),
+            note.created.map(|t| t.to_string()).unwrap_or_default(),
+            note.updated.map(|t| t.to_string()).unwrap_or_default(),
+            note.author.clone().unwrap_or_default(),
+        ]));
+    }
+    output
+}
+
+/// Format one CSV row, quoting fields that need it.
+fn csv_row(fields: &[String]) -> String {
+    let quoted: Vec<String> = fields
+        .iter()
+        .map(|f| {
+            if f.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", f.replace('"', "\"\""))
+            } else {
+                f.clone()
+            }
+        })
+        .collect();
+    format!("{}\n", quoted.join(","))
+}
+
+/// Parse CSV text into rows of fields. Quoted fields may contain commas,
+/// doubled quotes and newlines.
+fn parse_csv(input: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                '"' => in_quotes = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' => in_quotes = true,
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' => {}
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            c => field.push(c),
+        }
+    }
+
+    if in_quotes {
+        return Err("Unterminated quoted field".to_string());
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// Parse exported notes.
+pub fn parse_import(input: &str, format: Format) -> Result<Vec<(PathBuf, Note)>, String> {
+    match format {
+        Format::Json => parse_json(input),
+        Format::Csv => parse_csv_notes(input),
+    }
+}
+
+fn parse_json(input: &str) -> Result<Vec<(PathBuf, Note)>, String> {
+    let doc: Value = serde_json::from_str(input).map_err(|e| format!("Invalid JSON: {}", e))?;
+    // Accept a bare array of notes as well as a full export
+    let notes = doc
+        .get("notes")
+        .unwrap_or(&doc)
+        .as_array()
+        .ok_or("Expected a list of notes")?;
+
+    notes
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let path = entry["path"]
+                .as_str()
+                .ok_or_else(|| format!("Note {}: missing \"path\"", i + 1))?;
+            let text = entry["note"]
+                .as_str()
+                .ok_or_else(|| format!("Note {}: missing \"note\"", i + 1))?;
+
+            let mut note = Note::from_text(text.to_string());
+            note.created = entry["created"].as_i64();
+            note.updated = entry["updated"].as_i64();
+            note.author = entry["author"].as_str().map(String::from);
+            note.glob = entry["glob"].as_bool().unwrap_or(false);
+            note.inherit = entry["inherit"].as_bool().unwrap_or(false);
+            note.pending = entry["pending"].as_bool().unwrap_or(false);
+            note.expires = entry["expires"].as_i64();
+            note.private = entry["private"].as_bool().unwrap_or(false);
+            note.todo = entry["todo"].as_str().map(String::from);
+            note.warn = entry["warn"].as_str().map(String::from);
+            if let Some(history) = entry["history"].as_array() {
+                note.history = history
+                    .iter()
+                    .filter_map(|r| {
+                        Some(Revision {
+                            text: r["text"].as_str()?.to_string(),
+                            updated: r["updated"].as_i64(),
+                            author: r["author"].as_str().map(String::from),
+                        })
+                    })
+                    .collect();
+            }
+            Ok((PathBuf::from(path), note))
+        })
+        .collect()
+}
+
+fn parse_csv_notes(input: &str) -> Result<Vec<(PathBuf, Note)>, String> {
+    let mut rows = parse_csv(input)?.into_iter();
+    let header = rows.next().unwrap_or_default();
+    let column = |name: &str| header.iter().position(|h| h.trim() == name);
+    let path_col = column("path").ok_or("CSV is missing a \"path\" column")?;
+    let note_col = column("note").ok_or("CSV is missing a \"note\" column")?;
+    let created_col = column("created");
+    let updated_col = column("updated");
+    let author_col = column("author");
+
+    rows.enumerate()
+        .filter(|(_, row)| row.iter().any(|f| !f.is_empty()))
+        .map(|(i, row)| {
+            let get = |col: Option<usize>| {
+                col.and_then(|c| row.get(c))
+                    .filter(|f| !f.is_empty())
+                    .cloned()
+            };
+            let path = get(Some(path_col)).ok_or_else(|| format!("Row {}: missing path", i + 2))?;
+            let mut note = Note::from_text(get(Some(note_col)).unwrap_or_default());
+            note.created = get(created_col).and_then(|t| t.parse().ok());
+            note.updated = get(updated_col).and_then(|t| t.parse().ok());
+            note.author = get(author_col);
+            Ok((PathBuf::from(path), note))
+        })
+        .collect()
+}
+
+/// Import notes into `file`, rewriting paths that start with `rebase.0` to
+/// start with `rebase.1` instead.
+pub fn import_notes(
+    file: &NotesFile,
+    imported: Vec<(PathBuf, Note)>,
+    strategy: Strategy,
+    rebase: Option<(&Path, &Path)>,
+) -> Result<ImportSummary, String> {
+    let mut summary = ImportSummary::default();
+
+    file.update(|notes: &mut HashMap<PathBuf, Note>| {
+        for (path, mut note) in imported {
+            let path = match rebase {
+                Some((from, to)) => match path.strip_prefix(from) {
+                    Ok(rest) if rest.as_os_str().is_empty() => to.to_path_buf(),
+                    Ok(rest) => to.join(rest),
+                    Err(_) => path,
+                },
+                None => path,
+            };
+            if !path.is_absolute() {
+                return Err(format!("'{}' is not an absolute path", path.display()));
+            }
+
+            // Identity is machine-specific, so take it from the local file
+            let metadata = std::fs::metadata(&path).ok();
+            note.inode = metadata.as_ref().map(|m| (m.dev(), m.ino()));
+            note.stamp = metadata.as_ref().map(file_stamp);
+
+            match notes.remove(&path) {
+                None => {
+                    summary.added += 1;
+                    notes.insert(path, note);
+                }
+                Some(existing) if existing.text == note.text => {
+                    summary.skipped += 1;
+                    notes.insert(path, existing);
+                }
+                Some(existing) => {
+                    let replace = match strategy {
+                        Strategy::Overwrite => true,
+                        Strategy::SkipExisting => false,
+                        Strategy::Merge => note.updated > existing.updated,
+                    };
+                    if replace {
+                        summary.updated += 1;
+                        notes.insert(path, existing.revise(note));
+                    } else {
+                        summary.skipped += 1;
+                        notes.insert(path, existing);
+                    }
+                }
+            }
+        }
+        Ok(summary.added + summary.updated > 0)
+    })?;
+
+    Ok(summary)
+}